@@ -0,0 +1,149 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{prompt_string, structs};
+
+use crate::env;
+
+/// results of the models kept in `.readit/cache`, one json file per key,
+/// so a forced init only pays for what changed.
+///
+/// analyses are keyed by the file, the analyse model and the prompt version,
+/// vectors by the content, the embedding model and the dim
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    analyse_model: String,
+    prompt_version: String,
+    language: String,
+    embedding_model: String,
+    dim: usize,
+}
+
+impl Cache {
+    pub fn new(env: &env::Env) -> Self {
+        let dir = Path::new(&env.temp_dir).join("cache");
+        for sub in ["analysis", "embedding"] {
+            let _ = fs::create_dir_all(dir.join(sub));
+        }
+        Self {
+            dir,
+            analyse_model: env.config.analyse_model_id(),
+            prompt_version: prompt_string::PROMPT_VERSION.to_string(),
+            language: env.config.language(),
+            embedding_model: env.config.embedding_model_id(),
+            dim: env.config.dim(),
+        }
+    }
+
+    fn path(&self, kind: &str, key: String) -> PathBuf {
+        self.dir.join(kind).join(format!("{:x}.json", md5::compute(key)))
+    }
+
+    fn get<T: DeserializeOwned>(path: PathBuf) -> Option<T> {
+        let s = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&s).ok()
+    }
+
+    /// written next to the target and renamed, a reader never sees half a file
+    fn put<T: Serialize>(path: PathBuf, value: &T) {
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        if fs::write(&tmp, serde_json::to_string(value).unwrap()).is_ok() {
+            let _ = fs::rename(&tmp, &path);
+        }
+    }
+
+    fn analysis_path(&self, md5_value: &str, programming_lang: &str) -> PathBuf {
+        self.path("analysis", format!(
+            "{}:{}:{}:{}:{}",
+            md5_value, programming_lang, self.analyse_model, self.prompt_version, self.language
+        ))
+    }
+
+    pub fn analysis(&self, md5_value: &str, programming_lang: &str) -> Option<structs::GPTResponse> {
+        Self::get(self.analysis_path(md5_value, programming_lang))
+    }
+
+    pub fn has_analysis(&self, md5_value: &str, programming_lang: &str) -> bool {
+        self.analysis_path(md5_value, programming_lang).exists()
+    }
+
+    pub fn put_analysis(&self, md5_value: &str, programming_lang: &str, response: &structs::GPTResponse) {
+        Self::put(self.analysis_path(md5_value, programming_lang), response)
+    }
+
+    fn embedding_path(&self, content: &str) -> PathBuf {
+        self.path("embedding", format!("{}:{}:{}", self.embedding_model, self.dim, content))
+    }
+
+    pub fn embedding(&self, content: &str) -> Option<Vec<f32>> {
+        Self::get::<Vec<f32>>(self.embedding_path(content)).filter(|v| v.len() == self.dim)
+    }
+
+    pub fn put_embedding(&self, content: &str, vector: &Vec<f32>) {
+        Self::put(self.embedding_path(content), vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(dir: &Path) -> Cache {
+        for sub in ["analysis", "embedding"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        Cache {
+            dir: dir.to_path_buf(),
+            analyse_model: "openai:gpt-4o".to_string(),
+            prompt_version: prompt_string::PROMPT_VERSION.to_string(),
+            language: "English".to_string(),
+            embedding_model: "openai:text-embedding-3-large".to_string(),
+            dim: 3,
+        }
+    }
+
+    #[test]
+    fn analysis_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let c = cache(dir.path());
+        let response = structs::GPTResponse {
+            purpose: "adds".to_string(),
+            classes: vec![],
+            functions: vec![],
+        };
+        c.put_analysis("abc", "Rust", &response);
+        assert_eq!(c.analysis("abc", "Rust").unwrap().purpose, "adds");
+        assert!(!c.has_analysis("abd", "Rust"));
+        assert!(!c.has_analysis("abc", "C"));
+
+        for other in [
+            Cache { analyse_model: "openai:gpt-4o-mini".to_string(), ..c.clone() },
+            Cache { prompt_version: "0".to_string(), ..c.clone() },
+            Cache { language: "Chinese".to_string(), ..c.clone() },
+        ] {
+            assert!(!other.has_analysis("abc", "Rust"), "{:?}", other);
+        }
+        // the embedding model does not change an analysis
+        assert!(Cache { embedding_model: "local".to_string(), dim: 8, ..c.clone() }.has_analysis("abc", "Rust"));
+    }
+
+    #[test]
+    fn embedding_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let c = cache(dir.path());
+        c.put_embedding("fn add()", &vec![0.1, 0.2, 0.3]);
+        assert_eq!(c.embedding("fn add()"), Some(vec![0.1, 0.2, 0.3]));
+        assert_eq!(c.embedding("fn sub()"), None);
+
+        assert_eq!(Cache { embedding_model: "local".to_string(), ..c.clone() }.embedding("fn add()"), None);
+        assert_eq!(Cache { dim: 4, ..c.clone() }.embedding("fn add()"), None);
+        assert!(Cache { analyse_model: "ollama:qwen".to_string(), ..c.clone() }.embedding("fn add()").is_some());
+
+        // a vector of another size under the same key is not used
+        c.put_embedding("fn mul()", &vec![0.1, 0.2]);
+        assert_eq!(c.embedding("fn mul()"), None);
+    }
+}
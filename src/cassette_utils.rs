@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use arrow::array::Array;
+use arrow_array::Float32Array;
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{self, json, Value};
+
+use crate::structs;
+
+use crate::providers::{
+    self,
+    AskStream,
+    ChatProvider,
+    EmbeddingProvider,
+    ProviderError,
+};
+
+/// every request and response of the providers, one json object per line.
+///
+/// in record mode the real provider is called and the answer is appended,
+/// in replay mode the answer is read back and no request leaves the machine
+pub struct Cassette {
+    recorded: HashMap<String, Value>,
+    file: Option<Mutex<File>>,
+}
+
+impl Cassette {
+    pub fn record(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            recorded: HashMap::new(),
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    pub fn replay(path: &Path) -> io::Result<Self> {
+        let cassette = fs::read_to_string(path)?;
+        let mut recorded = HashMap::new();
+        for (n, line) in cassette.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            let broken = |why: String| io::Error::new(
+                io::ErrorKind::InvalidData, format!("line {} is broken, {}", n + 1, why)
+            );
+            let entry: Value = serde_json::from_str(line).map_err(|e| broken(e.to_string()))?;
+            let key = entry["key"].as_str().ok_or_else(|| broken("no key".to_string()))?;
+            recorded.insert(key.to_string(), entry["response"].clone());
+        }
+        Ok(Self {
+            recorded,
+            file: None,
+        })
+    }
+
+    fn key(method: &str, request: &Value) -> String {
+        format!("{:x}", md5::compute(format!("{}:{}", method, request)))
+    }
+
+    fn put<T: Serialize>(&self, method: &str, request: &Value, response: &T) -> Result<(), ProviderError> {
+        let entry = json!({
+            "key": Self::key(method, request),
+            "method": method,
+            "request": request,
+            "response": response,
+        });
+        if let Some(ref file) = self.file {
+            let line = format!("{}\n", entry);
+            // a writer that panicked left whole lines behind, the file is still good
+            file.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write_all(line.as_bytes())
+                .map_err(|e| ProviderError::Other(format!("can not write the cassette, {}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn get<T: DeserializeOwned>(&self, method: &str, request: &Value) -> Result<T, ProviderError> {
+        match self.recorded.get(&Self::key(method, request)) {
+            None => Err(ProviderError::Other(
+                format!("no recorded response for {} in the cassette", method)
+            )),
+            Some(response) => serde_json::from_value(response.clone())
+                .map_err(|e| ProviderError::Other(format!("broken cassette entry for {}: {}", method, e))),
+        }
+    }
+}
+
+/// records the wrapped chat provider, or replays without one
+pub struct CassetteChat {
+    inner: Option<Box<dyn ChatProvider>>,
+    cassette: Arc<Cassette>,
+}
+
+impl CassetteChat {
+    pub fn new(inner: Option<Box<dyn ChatProvider>>, cassette: Arc<Cassette>) -> Self {
+        Self { inner, cassette }
+    }
+
+    async fn record_stream(&self, method: &str, request: &Value, stream: AskStream)
+        -> Result<AskStream, ProviderError>
+    {
+        let chunks = stream.collect::<Vec<Result<String, ProviderError>>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<String>, ProviderError>>()?;
+        self.cassette.put(method, request, &chunks)?;
+        Ok(Box::pin(stream::iter(chunks.into_iter().map(Ok))))
+    }
+
+    fn replay_stream(&self, method: &str, request: &Value) -> Result<AskStream, ProviderError> {
+        let chunks: Vec<String> = self.cassette.get(method, request)?;
+        Ok(Box::pin(stream::iter(chunks.into_iter().map(Ok))))
+    }
+}
+
+#[async_trait]
+impl ChatProvider for CassetteChat {
+
+    async fn analyse_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<(structs::GPTResponse, structs::Usage), ProviderError>
+    {
+        let request = json!([code_string, programming_lang, language]);
+        if let Some(ref inner) = self.inner {
+            let response = inner.analyse_source(code_string, programming_lang, language).await?;
+            self.cassette.put("analyse_source", &request, &response)?;
+            return Ok(response);
+        }
+        self.cassette.get("analyse_source", &request)
+    }
+
+    async fn analyse_symbols(&self,
+        code_string: String, programming_lang: String, symbols: Vec<String>, language: String
+    ) -> Result<(structs::GPTSymbolsResponse, structs::Usage), ProviderError>
+    {
+        let request = json!([code_string, programming_lang, symbols, language]);
+        if let Some(ref inner) = self.inner {
+            let response = inner.analyse_symbols(code_string, programming_lang, symbols, language).await?;
+            self.cassette.put("analyse_symbols", &request, &response)?;
+            return Ok(response);
+        }
+        self.cassette.get("analyse_symbols", &request)
+    }
+
+    async fn split_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<structs::GPTCodeSplitResponse, ProviderError>
+    {
+        let request = json!([code_string, programming_lang, language]);
+        if let Some(ref inner) = self.inner {
+            let response = inner.split_source(code_string, programming_lang, language).await?;
+            self.cassette.put("split_source", &request, &response)?;
+            return Ok(response);
+        }
+        self.cassette.get("split_source", &request)
+    }
+
+    async fn ask(&self,
+        query: String, code_list: Vec<String>, language: String
+    ) -> Result<AskStream, ProviderError>
+    {
+        let request = json!([query, code_list, language]);
+        if let Some(ref inner) = self.inner {
+            let stream = inner.ask(query, code_list, language).await?;
+            return self.record_stream("ask", &request, stream).await;
+        }
+        self.replay_stream("ask", &request)
+    }
+
+    async fn chat(&self, message: String) -> Result<AskStream, ProviderError> {
+        let request = json!([message]);
+        if let Some(ref inner) = self.inner {
+            let stream = inner.chat(message).await?;
+            return self.record_stream("chat", &request, stream).await;
+        }
+        self.replay_stream("chat", &request)
+    }
+
+    async fn summarize(&self, query: String, language: String)
+        -> Result<(String, structs::Usage), ProviderError>
+    {
+        let request = json!([query, language]);
+        if let Some(ref inner) = self.inner {
+            let response = inner.summarize(query, language).await?;
+            self.cassette.put("summarize", &request, &response)?;
+            return Ok(response);
+        }
+        self.cassette.get("summarize", &request)
+    }
+
+    async fn merge_purposes(&self,
+        parts: Vec<String>, programming_lang: String, language: String
+    ) -> Result<(String, structs::Usage), ProviderError>
+    {
+        let request = json!([parts, programming_lang, language]);
+        if let Some(ref inner) = self.inner {
+            let response = inner.merge_purposes(parts, programming_lang, language).await?;
+            self.cassette.put("merge_purposes", &request, &response)?;
+            return Ok(response);
+        }
+        self.cassette.get("merge_purposes", &request)
+    }
+}
+
+/// records the wrapped embedding provider, or replays without one
+pub struct CassetteEmbedding {
+    inner: Option<Box<dyn EmbeddingProvider>>,
+    cassette: Arc<Cassette>,
+}
+
+impl CassetteEmbedding {
+    pub fn new(inner: Option<Box<dyn EmbeddingProvider>>, cassette: Arc<Cassette>) -> Self {
+        Self { inner, cassette }
+    }
+}
+
+/// every input string is its own entry, the embedding cache leaves out
+/// other strings on every run, so the batches never come back the same
+#[async_trait]
+impl EmbeddingProvider for CassetteEmbedding {
+
+    async fn embedding_compute(&self, source: Arc<dyn Array>)
+        -> Result<(Float32Array, u32), ProviderError>
+    {
+        let input = providers::array_strings(&source);
+        let mut embedding: Vec<f32> = Vec::new();
+        let mut tokens = 0;
+        match self.inner {
+            Some(ref inner) => {
+                let (e, t) = inner.embedding_compute(source).await?;
+                let n = input.len().max(1);
+                let dim = (e.len() / n).max(1);
+                for (i, (text, vector)) in input.iter().zip(e.values().chunks(dim)).enumerate() {
+                    // the tokens of the batch, shared out as evenly as they go
+                    let share = t / n as u32 + if i < t as usize % n { 1 } else { 0 };
+                    self.cassette.put("embedding", &json!(text), &(vector, share))?;
+                }
+                embedding.extend_from_slice(e.values());
+                tokens = t;
+            },
+            None => for text in input.iter() {
+                let (vector, t): (Vec<f32>, u32) = self.cassette.get("embedding", &json!(text))?;
+                embedding.extend(vector);
+                tokens += t;
+            },
+        }
+        Ok((Float32Array::from(embedding), tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_creates_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".readit/cassette.jsonl");
+        let cassette = Cassette::record(&path).unwrap();
+        cassette.put("summarize", &json!(["q", "English"]), &("answer", 3)).unwrap();
+
+        let replayed = Cassette::replay(&path).unwrap();
+        let (answer, tokens): (String, u32) = replayed.get("summarize", &json!(["q", "English"])).unwrap();
+        assert_eq!((answer.as_str(), tokens), ("answer", 3));
+    }
+
+    #[test]
+    fn broken_line_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassette.jsonl");
+        fs::write(&path, "{\"key\": \"a\", \"response\": 1}\n{\"key\": \n").unwrap();
+        let e = Cassette::replay(&path).err().unwrap();
+        assert!(e.to_string().contains("line 2"), "{}", e);
+
+        assert!(Cassette::replay(&dir.path().join("missing.jsonl")).is_err());
+    }
+}
@@ -1,26 +1,89 @@
+use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::path::Path;
 use serde::{Deserialize, Serialize};
-use serde_yml;
+use serde_yml::{self, Mapping, Value};
+
+/// USD per 1M tokens
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    pub chat_provider: Option<String>,      // "openai", "azure", "ollama", "llamacpp"
+    pub embedding_provider: Option<String>, // "openai", "azure", "local"
     pub openai_key: Option<String>,
     pub openai_base: Option<String>,
+    pub azure_base: Option<String>,         // https://{resource}.openai.azure.com
+    pub azure_key: Option<String>,
+    pub azure_api_version: Option<String>,
+    pub azure_chat_deployment: Option<String>,
+    pub azure_analyse_deployment: Option<String>,
+    pub azure_embedding_deployment: Option<String>,
+    pub ollama_base: Option<String>,
+    pub llamacpp_base: Option<String>,
     pub chat_model: Option<String>,
     pub analyse_model: Option<String>,
     pub embedding_model: Option<String>,
     pub dim: Option<usize>,
+    pub embedding_max_tokens: Option<usize>, // longer contents are embedded in chunks
+    pub chunk_overlap: Option<usize>,       // tokens shared by neighbouring chunks
+    pub analyse_max_tokens: Option<usize>,  // largest analyse_source prompt
+    pub local_model_path: Option<String>,   // directory of the local embedding model
     pub language: Option<String>,
+    pub cassette: Option<String>,           // "record", "replay", .readit/cassette.jsonl
+    pub retries: Option<u32>,               // retries of a failed api call
+    pub retry_delay_ms: Option<u64>,        // first backoff, doubled on every retry
+    pub retry_max_delay_ms: Option<u64>,
+    pub prices: Option<HashMap<String, Price>>, // model -> price, over the built-in table
+    pub max_tokens: Option<u64>,            // spend limit of a command, 0 is no limit
+    pub max_cost: Option<f64>,              // in USD, 0 is no limit
+    pub workers: Option<usize>,             // files analysed at the same time by init
+    pub json_schema: Option<bool>,          // strict schema for analyse_source, off for servers without it
+    pub max_file_size: Option<u64>,         // bytes, larger files are skipped
+    pub max_line_length: Option<usize>,     // average, files over it are taken as minified and skipped
+    pub docs: Option<bool>,                 // index markdown and rst by section too
 }
 
 impl Config {
+    pub fn chat_provider(&self) -> String {
+        self.chat_provider.clone().unwrap_or("openai".to_string())
+    }
+    pub fn embedding_provider(&self) -> String {
+        self.embedding_provider.clone().unwrap_or("openai".to_string())
+    }
     pub fn openai_key(&self) -> String {
-        self.openai_key.clone().unwrap()
+        self.openai_key.clone().unwrap_or("".to_string())
     }
     pub fn openai_base(&self) -> String {
         self.openai_base.clone().unwrap_or("https://api.openai.com/v1".to_string())
     }
+    pub fn azure_base(&self) -> String {
+        self.azure_base.clone().unwrap_or("".to_string())
+    }
+    pub fn azure_api_version(&self) -> String {
+        self.azure_api_version.clone().unwrap_or("2024-08-01-preview".to_string())
+    }
+    /// deployments default to the model names
+    pub fn azure_chat_deployment(&self) -> String {
+        self.azure_chat_deployment.clone().unwrap_or(self.chat_model())
+    }
+    pub fn azure_analyse_deployment(&self) -> String {
+        self.azure_analyse_deployment.clone().unwrap_or(self.analyse_model())
+    }
+    pub fn azure_embedding_deployment(&self) -> String {
+        self.azure_embedding_deployment.clone().unwrap_or(self.embedding_model())
+    }
+    pub fn ollama_base(&self) -> String {
+        self.ollama_base.clone().unwrap_or("http://localhost:11434".to_string())
+    }
+    pub fn llamacpp_base(&self) -> String {
+        self.llamacpp_base.clone().unwrap_or("http://localhost:8080".to_string())
+    }
     pub fn chat_model(&self) -> String {
         self.chat_model.clone().unwrap_or("gpt-4o".to_string())
     }
@@ -30,21 +93,199 @@ impl Config {
     pub fn embedding_model(&self) -> String {
         self.embedding_model.clone().unwrap_or("text-embedding-3-large".to_string())
     }
+    /// the embedding model and where it runs, vectors of the same one compare
+    pub fn embedding_model_id(&self) -> String {
+        match self.embedding_provider().as_str() {
+            "local" => format!("local:{}", self.local_model_path()),
+            p => format!("{}:{}", p, self.embedding_model()),
+        }
+    }
+    pub fn analyse_model_id(&self) -> String {
+        format!("{}:{}", self.chat_provider(), self.analyse_model())
+    }
     pub fn dim(&self) -> usize {
         //self.dim.unwrap_or(265)
         self.dim.unwrap_or(1024)
     }
 
+    pub fn embedding_max_tokens(&self) -> usize {
+        self.embedding_max_tokens.unwrap_or(8191)
+    }
+    pub fn chunk_overlap(&self) -> usize {
+        self.chunk_overlap.unwrap_or(200)
+    }
+    pub fn analyse_max_tokens(&self) -> usize {
+        self.analyse_max_tokens.unwrap_or(100_000)
+    }
+
+    pub fn local_model_path(&self) -> String {
+        self.local_model_path.clone().unwrap_or("".to_string())
+    }
+
     pub fn language(&self) -> String {
         self.language.clone().unwrap_or("".to_string())
     }
 
+    pub fn cassette(&self) -> String {
+        self.cassette.clone().unwrap_or("".to_string())
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(6)
+    }
+    pub fn retry_delay_ms(&self) -> u64 {
+        self.retry_delay_ms.unwrap_or(1000)
+    }
+    pub fn retry_max_delay_ms(&self) -> u64 {
+        self.retry_max_delay_ms.unwrap_or(60000)
+    }
+
+    pub fn max_tokens(&self) -> u64 {
+        self.max_tokens.unwrap_or(0)
+    }
+    pub fn max_cost(&self) -> f64 {
+        self.max_cost.unwrap_or(0.0)
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.unwrap_or(8).max(1)
+    }
+
+    pub fn json_schema(&self) -> bool {
+        self.json_schema.unwrap_or(true)
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size.unwrap_or(1_000_000)
+    }
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length.unwrap_or(500)
+    }
+    pub fn docs(&self) -> bool {
+        self.docs.unwrap_or(false)
+    }
+
+    /// the configured price of a model, or the built-in one
+    pub fn price(&self, model: &str) -> Option<Price> {
+        if let Some(p) = self.prices.as_ref().and_then(|p| p.get(model)) {
+            return Some(*p);
+        }
+        let (prompt, completion) = match model {
+            // runs on the machine, ollama and llama.cpp servers too
+            "local" | "llamacpp" => (0.0, 0.0),
+            m if m.starts_with("ollama:") => (0.0, 0.0),
+            "gpt-4o" => (2.5, 10.0),
+            "gpt-4o-mini" => (0.15, 0.6),
+            "gpt-4-turbo" => (10.0, 30.0),
+            "gpt-3.5-turbo" => (0.5, 1.5),
+            "text-embedding-3-large" => (0.13, 0.0),
+            "text-embedding-3-small" => (0.02, 0.0),
+            "text-embedding-ada-002" => (0.1, 0.0),
+            _ => return None,
+        };
+        Some(Price { prompt, completion })
+    }
+
     pub fn new_from_path(path: &Path) -> Self {
         let file = fs::read_to_string(path).unwrap();
         let config:Self  = serde_yml::from_str(&file).unwrap();
         config
     }
 
+    /// the names of the fields, `chat_model`
+    fn fields() -> Vec<String> {
+        let empty: Self = serde_yml::from_str("{}").unwrap();
+        match serde_yml::to_value(&empty).unwrap() {
+            Value::Mapping(m) => m.keys().filter_map(|k| k.as_str()).map(|k| k.to_string()).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// the fields a yaml file sets, empty when there is no file
+    fn layer_from_path(path: &Path) -> Mapping {
+        if !path.exists() {
+            return Mapping::new();
+        }
+        let file = fs::read_to_string(path).unwrap();
+        match serde_yml::from_str(&file) {
+            Ok(Value::Mapping(m)) => m,
+            Ok(Value::Null) => Mapping::new(),
+            _ => panic!("{} is not a mapping of config fields", path.display()),
+        }
+    }
+
+    /// `READIT_CHAT_MODEL=gpt-4o-mini` sets chat_model
+    fn layer_from_env() -> Mapping {
+        let mut layer = Mapping::new();
+        // the variables readit read before READIT_*, which win over them
+        for (name, field) in [
+            ("OPENAI_KEY", "openai_key"),
+            ("OPENAI_BASE", "openai_base"),
+            ("AZURE_OPENAI_KEY", "azure_key"),
+        ] {
+            if let Ok(value) = env::var(name) {
+                layer.insert(Value::from(field), Value::from(value));
+            }
+        }
+        for field in Self::fields() {
+            if let Ok(value) = env::var(format!("READIT_{}", field.to_uppercase())) {
+                layer.insert(Value::from(field), serde_yml::from_str(&value).unwrap_or(Value::from(value)));
+            }
+        }
+        layer
+    }
+
+    /// `-c chat_model=gpt-4o-mini`, the value is read as yaml, `-c workers=4`.
+    /// checked by clap, so a mistake is a usage error and not a panic
+    pub fn parse_arg(arg: &str) -> Result<(String, Value), String> {
+        let (key, value) = match arg.split_once('=') {
+            Some((k, v)) => (k.trim(), v),
+            None => return Err(format!("{} is not KEY=VALUE", arg)),
+        };
+        if !Self::fields().iter().any(|f| f == key) {
+            return Err(format!("there is no config field {}", key));
+        }
+        let value = serde_yml::from_str(value).unwrap_or(Value::from(value));
+        let mut field = Mapping::new();
+        field.insert(Value::from(key), value.clone());
+        if let Err(e) = serde_yml::from_value::<Self>(Value::Mapping(field)) {
+            return Err(format!("{}: {}", key, e));
+        }
+        Ok((key.to_string(), value))
+    }
+
+    fn layer_from_args(args: &[String]) -> Mapping {
+        let mut layer = Mapping::new();
+        for (key, value) in args.iter().filter_map(|arg| Self::parse_arg(arg).ok()) {
+            layer.insert(Value::from(key), value);
+        }
+        layer
+    }
+
+    /// the config of a project, field by field the first of: `-c key=value`
+    /// arguments, `READIT_*` environment variables, the project's
+    /// `.readit/config.yaml`, the global `~/.readit/config.yaml`, the defaults
+    pub fn layered(global: &Path, project: &Path, args: &[String]) -> Self {
+        let mut config = Mapping::new();
+        for layer in [
+            Self::layer_from_path(global),
+            Self::layer_from_path(project),
+            Self::layer_from_env(),
+            Self::layer_from_args(args),
+        ] {
+            for (k, v) in layer {
+                // a field left empty in a file does not hide the one below
+                if !v.is_null() {
+                    config.insert(k, v);
+                }
+            }
+        }
+        match serde_yml::from_value(Value::Mapping(config)) {
+            Ok(c) => c,
+            Err(e) => panic!("invalid config: {}", e),
+        }
+    }
+
     pub fn save(&self, path: &Path) {
         let config_string = serde_yml::to_string(&self).unwrap();
         fs::write(path, config_string).unwrap();
@@ -52,13 +293,39 @@ impl Config {
 
     pub fn init_config(file_path: &Path) -> Self {
         let config = Self {
+            chat_provider   : Some("openai".to_string()),
+            embedding_provider: Some("openai".to_string()),
             openai_key      : Some("".to_string()),
             openai_base     : Some("https://api.openai.com/v1".to_string()),
+            azure_base      : Some("".to_string()),
+            azure_key       : Some("".to_string()),
+            azure_api_version: Some("2024-08-01-preview".to_string()),
+            azure_chat_deployment: None,
+            azure_analyse_deployment: None,
+            azure_embedding_deployment: None,
+            ollama_base     : Some("http://localhost:11434".to_string()),
+            llamacpp_base   : Some("http://localhost:8080".to_string()),
             chat_model      : Some("gpt-4o".to_string()),
             analyse_model   : Some("gpt-4o".to_string()),
             embedding_model : Some("text-embedding-3-large".to_string()),
-            dim             : Some(256),
+            dim             : Some(1024),
+            embedding_max_tokens: Some(8191),
+            chunk_overlap   : Some(200),
+            analyse_max_tokens: Some(100_000),
+            local_model_path: Some("".to_string()),
             language        : Some("".to_string()),
+            cassette        : None,
+            retries         : Some(6),
+            retry_delay_ms  : Some(1000),
+            retry_max_delay_ms: Some(60000),
+            prices          : None,
+            max_tokens      : Some(0),
+            max_cost        : Some(0.0),
+            workers         : Some(8),
+            json_schema     : Some(true),
+            max_file_size   : Some(1_000_000),
+            max_line_length : Some(500),
+            docs            : Some(false),
         };
         let config_string = serde_yml::to_string(&config).unwrap();
         fs::write(file_path, config_string).unwrap();
@@ -66,3 +333,68 @@ impl Config {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layered_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = dir.path().join("global.yaml");
+        let project = dir.path().join("project.yaml");
+        fs::write(&global, "chat_model: global\nanalyse_model: global\nworkers: 2\nllamacpp_base: http://global\n").unwrap();
+        fs::write(&project, "chat_model: project\nanalyse_model: project\nworkers:\n").unwrap();
+        // no other test reads this field
+        env::set_var("READIT_LLAMACPP_BASE", "http://env");
+
+        let config = Config::layered(&global, &project, &["analyse_model=arg".to_string()]);
+        assert_eq!(config.chat_model(), "project");
+        assert_eq!(config.analyse_model(), "arg");
+        // left empty in the project file
+        assert_eq!(config.workers(), 2);
+        assert_eq!(config.llamacpp_base(), "http://env");
+        // no layer sets it
+        assert_eq!(config.embedding_provider(), "openai");
+        env::remove_var("READIT_LLAMACPP_BASE");
+    }
+
+    #[test]
+    fn layered_openai_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let (global, project) = (dir.path().join("a.yaml"), dir.path().join("b.yaml"));
+        fs::write(&project, "azure_key: project\n").unwrap();
+        // no other test reads this field
+        env::set_var("AZURE_OPENAI_KEY", "variable");
+
+        assert_eq!(Config::layered(&global, &project, &[]).azure_key.unwrap(), "variable");
+        let config = Config::layered(&global, &project, &["azure_key=arg".to_string()]);
+        assert_eq!(config.azure_key.unwrap(), "arg");
+        env::set_var("READIT_AZURE_KEY", "readit");
+        assert_eq!(Config::layered(&global, &project, &[]).azure_key.unwrap(), "readit");
+        env::remove_var("READIT_AZURE_KEY");
+        env::remove_var("AZURE_OPENAI_KEY");
+    }
+
+    #[test]
+    fn layered_without_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::layered(&dir.path().join("a.yaml"), &dir.path().join("b.yaml"), &[]);
+        assert_eq!(config.chat_provider(), "openai");
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(
+            Config::parse_arg("workers=4"),
+            Ok(("workers".to_string(), Value::from(4)))
+        );
+        assert_eq!(
+            Config::parse_arg("chat_model=gpt-4o-mini"),
+            Ok(("chat_model".to_string(), Value::from("gpt-4o-mini")))
+        );
+        assert!(Config::parse_arg("workers").is_err());
+        assert!(Config::parse_arg("no_such_field=1").is_err());
+        assert!(Config::parse_arg("workers=many").is_err());
+    }
+}
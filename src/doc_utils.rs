@@ -0,0 +1,216 @@
+use crate::ignore_rules::Ignore;
+use crate::language_extensions::LanguageExtensions;
+
+/// the documentation formats indexed in docs mode, by extension
+const DOC_EXTENSIONS: [(&str, &str); 4] = [
+    (".md", "Markdown"),
+    (".markdown", "Markdown"),
+    (".mdx", "Markdown"),
+    (".rst", "reStructuredText"),
+];
+
+/// a part of a document under one heading
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub title: String,      // the headings down to this one, `Install > Linux`
+    pub start_byte: usize,
+    pub end_byte: usize,
+}
+
+pub fn is_doc(programming_lang: &str) -> bool {
+    programming_lang == "Markdown" || programming_lang == "reStructuredText"
+}
+
+/// docs mode: the doc extensions are no longer ignored and map to their format
+pub fn enable(ignore: &mut Ignore, language_extensions: &mut LanguageExtensions) {
+    let is_doc_file = |f: &String| DOC_EXTENSIONS.iter().any(|(ext, _)| f.ends_with(ext));
+    ignore.files.retain(|f| !is_doc_file(f));
+    ignore.file_posfix.retain(|f| !is_doc_file(f));
+    for (ext, lang) in DOC_EXTENSIONS {
+        language_extensions.ext_type_map.insert(ext.to_string(), lang.to_string());
+        if !language_extensions.ext_list.contains(&ext.to_string()) {
+            language_extensions.ext_list.push(ext.to_string());
+        }
+    }
+}
+
+/// `## Title` gives (2, "Title")
+fn atx_heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') && !rest.starts_with('\t') {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim().to_string()))
+}
+
+/// a line made of one punctuation character repeated, `=====`
+fn adornment(line: &str) -> Option<char> {
+    let line = line.trim_end();
+    let c = line.chars().next()?;
+    if line.len() < 2 || !c.is_ascii_punctuation() || !line.chars().all(|x| x == c) {
+        return None;
+    }
+    Some(c)
+}
+
+/// (byte offset, level, title) of the headings of a markdown text,
+/// `#` headings and underlined ones, not the ones in fenced code
+fn markdown_headings(lines: &[(usize, &str)]) -> Vec<(usize, usize, String)> {
+    let mut headings = Vec::new();
+    let mut fence: Option<&str> = None;
+    for (i, (offset, line)) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        if let Some(f) = fence {
+            if trimmed.starts_with(f) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            continue;
+        }
+        if let Some((level, title)) = atx_heading(line) {
+            headings.push((*offset, level, title));
+            continue;
+        }
+        // Title
+        // =====
+        if let Some((_, next)) = lines.get(i + 1) {
+            let level = match adornment(next) {
+                Some('=') => 1,
+                Some('-') => 2,
+                _ => continue,
+            };
+            if !line.trim().is_empty() && !line.starts_with(' ') {
+                headings.push((*offset, level, line.trim().to_string()));
+            }
+        }
+    }
+    headings
+}
+
+/// (byte offset, level, title) of the headings of a restructuredtext text.
+/// levels follow the order the adornment styles first appear in, as in rst
+fn rst_headings(lines: &[(usize, &str)]) -> Vec<(usize, usize, String)> {
+    let mut headings = Vec::new();
+    let mut styles: Vec<(char, bool)> = Vec::new();
+    let mut i = 0;
+    while i + 1 < lines.len() {
+        let (offset, line) = lines[i];
+        // ======
+        // Title
+        // ======
+        let (overline, title, under, skip) = match adornment(line) {
+            Some(c) if i + 2 < lines.len() => (Some(c), lines[i + 1].1, lines[i + 2].1, 3),
+            _ => (None, line, lines[i + 1].1, 2),
+        };
+        let is_heading = !title.trim().is_empty()
+            && adornment(title).is_none()
+            && match (overline, adornment(under)) {
+                (Some(o), Some(u)) => o == u,
+                (None, Some(_)) => !title.starts_with(' ') && under.trim_end().len() >= title.trim_end().len(),
+                _ => false,
+            };
+        if !is_heading {
+            i += 1;
+            continue;
+        }
+        let style = (adornment(under).unwrap(), overline.is_some());
+        let level = match styles.iter().position(|s| *s == style) {
+            Some(p) => p + 1,
+            None => {
+                styles.push(style);
+                styles.len()
+            },
+        };
+        headings.push((offset, level, title.trim().to_string()));
+        i += skip;
+    }
+    headings
+}
+
+/// the sections of a document, split before every heading. the text before
+/// the first heading is a section named after the file
+pub fn sections(programming_lang: &str, file_name: &str, text: &str) -> Vec<Section> {
+    let mut lines: Vec<(usize, &str)> = Vec::new();
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        lines.push((offset, line.trim_end_matches(['\n', '\r'])));
+        offset += line.len();
+    }
+    let headings = match programming_lang {
+        "reStructuredText" => rst_headings(&lines),
+        _ => markdown_headings(&lines),
+    };
+
+    let mut sections = Vec::new();
+    let mut path: Vec<(usize, String)> = Vec::new();
+    let mut start = 0;
+    let mut title = file_name.to_string();
+    for (offset, level, heading) in headings {
+        if !text[start..offset].trim().is_empty() {
+            sections.push(Section { title: title.clone(), start_byte: start, end_byte: offset });
+        }
+        while path.last().is_some_and(|(l, _)| *l >= level) {
+            path.pop();
+        }
+        path.push((level, heading));
+        title = path.iter().map(|(_, h)| h.as_str()).collect::<Vec<&str>>().join(" > ");
+        start = offset;
+    }
+    if !text[start..].trim().is_empty() {
+        sections.push(Section { title, start_byte: start, end_byte: text.len() });
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn titles(sections: &[Section]) -> Vec<&str> {
+        sections.iter().map(|s| s.title.as_str()).collect()
+    }
+
+    #[test]
+    fn markdown_sections() {
+        let text = "intro\n\n# Install\n\ntext\n\n## Linux\n\n```sh\n# not a heading\n```\n\n## Mac ##\n\nmore\n\nUsage\n=====\n\nrun it\n";
+        let sections = sections("Markdown", "README.md", text);
+        assert_eq!(
+            titles(&sections),
+            vec!["README.md", "Install", "Install > Linux", "Install > Mac", "Usage"]
+        );
+        assert_eq!(sections[0].start_byte, 0);
+        assert_eq!(&text[sections[0].start_byte..sections[0].end_byte], "intro\n\n");
+        assert_eq!(sections.last().unwrap().end_byte, text.len());
+        for w in sections.windows(2) {
+            assert_eq!(w[0].end_byte, w[1].start_byte);
+        }
+    }
+
+    #[test]
+    fn markdown_without_text_before_the_first_heading() {
+        let sections = sections("Markdown", "a.md", "# A\n\ntext\n#hashtag\n");
+        assert_eq!(titles(&sections), vec!["A"]);
+    }
+
+    #[test]
+    fn rst_sections() {
+        let text = "=====\nTitle\n=====\n\nintro\n\nInstall\n-------\n\ntext\n\nLinux\n~~~~~\n\nmore\n\nUsage\n-----\n\nrun it\n";
+        let sections = sections("reStructuredText", "index.rst", text);
+        assert_eq!(
+            titles(&sections),
+            vec!["Title", "Title > Install", "Title > Install > Linux", "Title > Usage"]
+        );
+    }
+
+    #[test]
+    fn empty_document() {
+        assert!(sections("Markdown", "a.md", "\n\n").is_empty());
+    }
+}
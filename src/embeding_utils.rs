@@ -1,41 +1,27 @@
-use tokio;
 use std::{iter::once, sync::Arc};
-use std::path::{Path, PathBuf};
-
-use arrow::{
-    buffer::Buffer,
-    array::{
-        Float32Builder,
-        ArrayData,
-        Array,
-        ListArray,
-    }
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use arrow::array::{
+    new_null_array,
+    Array,
 };
 use arrow_array::{
-    cast::{
-        as_map_array,
-        AsArray
-    },
-    types::Float32Type,
     FixedSizeListArray,
-    PrimitiveArray,
     Float32Array,
     Int32Array,
     RecordBatch,
     RecordBatchIterator,
     StringArray,
-    builder::{
-        FixedSizeListBuilder,
-        PrimitiveBuilder,
-    },
+    builder::FixedSizeListBuilder,
 };
 
 
 use arrow_schema::{DataType, Field, Schema};
 
-use futures::{StreamExt, TryStreamExt};
+use futures::TryStreamExt;
 
-use async_openai::error::OpenAIError;
 
 use lancedb::{
     connect, connection::CreateTableMode, 
@@ -44,63 +30,172 @@ use lancedb::{
     Connection, Result
 };
 
-use crate::structs;
+use crate::{prompt_string, structs};
+use crate::notebook_utils;
 
-use crate::openai_utils;
+use crate::providers::{ProviderError, Providers};
 
 use crate::env;
 
 use crate::pooling;
 
-const TABLE_NAME: &str = &"vectors";
+use crate::tokenizer_utils::TokenCounter;
+
+use crate::local_embedding_utils;
+
+use crate::cache_utils::Cache;
+
+const TABLE_NAME: &str = "vectors";
+const META_TABLE_NAME: &str = "meta";
+
+/// version of the vectors table, 2 added the location columns
+const SCHEMA_VERSION: u32 = 2;
+
+/// limits of one batched embedding request
+const MAX_BATCH_ITEMS: usize = 256;
+const MAX_BATCH_TOKENS: usize = 200_000;
+
+/// what an index was built with, kept in the meta table
+#[derive(Debug, Clone, PartialEq)]
+struct Meta {
+    schema_version: u32,
+    embedding_model: String,
+    dim: usize,
+    analyse_model: String,
+    prompt_version: String,
+}
+
+impl Meta {
+    /// what the config builds now
+    fn new(env: &env::Env) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            embedding_model: env.config.embedding_model_id(),
+            dim: env.config.dim(),
+            analyse_model: env.config.analyse_model_id(),
+            prompt_version: prompt_string::PROMPT_VERSION.to_string(),
+        }
+    }
+
+    fn rows(&self) -> Vec<(&str, String)> {
+        vec![
+            ("schema_version", self.schema_version.to_string()),
+            ("embedding_model", self.embedding_model.clone()),
+            ("dim", self.dim.to_string()),
+            ("analyse_model", self.analyse_model.clone()),
+            ("prompt_version", self.prompt_version.clone()),
+        ]
+    }
+}
 
-const MAX_TOKENS: usize = 8192;
+fn embedding_error(e: ProviderError) -> lancedb::Error {
+    lancedb::Error::Runtime { message: format!("embedding error, {}", e) }
+}
 
-pub struct Embedding <'a> {
-    client: &'a openai_utils::OpenAI,
+/// an index used in a way it was not built for, main reports it and stops
+fn refuse(message: String) -> lancedb::Error {
+    lancedb::Error::Runtime { message }
+}
+
+/// one connection and table handle, shared by the workers of init
+pub struct Embedding {
+    client: Arc<Providers>,
     db: Connection,
     table: Table,
     dim: usize,
+    tokenizer: TokenCounter,
+    max_tokens: usize,
+    overlap: usize,
+    cache: Cache,
 }
 
-impl <'a> Embedding <'a> {
-    pub async fn new(env: &env::Env, client: &'a openai_utils::OpenAI) -> Result<Self> {
+impl Embedding {
+    /// opens the index of the project and checks it was built the way the
+    /// config says. `rebuild` is for a forced init, which analyses and embeds
+    /// everything again, so an index built another way is replaced
+    pub async fn new(env: &env::Env, client: Arc<Providers>, rebuild: bool) -> Result<Self> {
 
         let path = env.work_dir().join(".readit").join("db");
 
         let db = connect(path.as_path().to_str().unwrap()).execute().await?;
-        let table = Self::open_table(&db, env.config.dim() as i32).await?;
+        let current = Meta::new(env);
+        let (table, stored, has_meta) = Self::open_table(&db, &current).await?;
+
+        // the local model cuts its input off much earlier than the api ones
+        let (tokenizer, max_tokens) = match env.config.embedding_provider().as_str() {
+            "local" => match local_embedding_utils::token_counter(env) {
+                Ok((t, n)) => (t, n.min(env.config.embedding_max_tokens())),
+                Err(e) => return Err(lancedb::Error::Runtime {
+                    message: format!("failed to load the local embedding model, {}", e)
+                }),
+            },
+            _ => (TokenCounter::new(&env.config.embedding_model()), env.config.embedding_max_tokens()),
+        };
 
-        Ok(Self {
+        let mut embedding = Self {
             client,
             db,
             table,
-            dim: env.config.dim() as usize,
-        })
+            dim: env.config.dim(),
+            tokenizer,
+            max_tokens,
+            overlap: env.config.chunk_overlap().min(max_tokens / 2),
+            cache: Cache::new(env),
+        };
+
+        if stored != current {
+            if rebuild {
+                if stored.dim != current.dim {
+                    embedding.table = Self::init_table(&embedding.db, current.dim as i32).await?;
+                }
+            } else {
+                embedding.check(&stored, &current).await?;
+            }
+        }
+        if stored != current || !has_meta || rebuild {
+            Self::write_meta(&embedding.db, &current).await?;
+        }
+
+        // older versions stored absolute paths
+        let (rebased, left, tokens) = embedding.rebase_paths(&env.work_dir().canonicalize().unwrap()).await?;
+        if rebased > 0 {
+            println!(
+                "{} files in the index now have paths relative to the project, embedding use tokens: {}",
+                rebased, tokens
+            );
+        }
+        if left > 0 {
+            println!(
+                "{} files in the index have absolute paths outside the project, run \"readit rebase-paths\" if the project was moved",
+                left
+            );
+        }
+        Ok(embedding)
     }
 
     fn get_schema(dim: i32) -> Arc<Schema>{
-        let schema = Arc::new(Schema::new(vec![
+        Arc::new(Schema::new(vec![
             //Field::new("id", DataType::Int32, true),
-            //Field::new("line_number" , DataType::Int32   , false)    ,
-            //Field::new("lines"       , DataType::Int32   , false)    ,
             Field::new("file"        , DataType::Utf8    , false)    ,
             Field::new("md5"         , DataType::Utf8    , false)    ,
-            Field::new("code_type"   , DataType::Utf8    , false)    , // "file" , "class" , "function"
+            Field::new("code_type"   , DataType::Utf8    , false)    , // "file" , "class" , "function", "doc", "failed"
             Field::new("lang"        , DataType::Utf8    , false)    ,
             Field::new("name"        , DataType::Utf8    , false)    ,
             Field::new("purpose"     , DataType::Utf8    , false)    ,
             Field::new("content"     , DataType::Utf8    , false)    ,  // name + purpose + code
+            Field::new("start_line"  , DataType::Int32   , true)     ,  // 1 based, null when unknown
+            Field::new("end_line"    , DataType::Int32   , true)     ,
+            Field::new("start_byte"  , DataType::Int32   , true)     ,
+            Field::new("end_byte"    , DataType::Int32   , true)     ,
             Field::new(
                 "embedings", 
                 DataType::FixedSizeList(
                     Arc::new(Field::new("item", DataType::Float32, true)),
-                    dim as i32,
+                    dim,
                 ),
                 false
             ),  // name + purpose + code
-        ]));
-        schema
+        ]))
     }
 
     async fn init_table(db: &Connection, dim:i32) -> Result<Table>{
@@ -111,115 +206,362 @@ impl <'a> Embedding <'a> {
             .await
     }
 
-    async fn open_table(db: &Connection, dim: i32) -> Result<Table> {
+    /// the vectors table, what it was built with and whether the meta table
+    /// said so. a new table is built the current way, older tables are
+    /// migrated to the current schema
+    async fn open_table(db: &Connection, current: &Meta) -> Result<(Table, Meta, bool)> {
 
         let table = match db.open_table("vectors").execute().await {
             Err(_) => {
                 println!("no table");
-                let t = Self::init_table(db, dim).await?;
-                t
+                let t = Self::init_table(db, current.dim as i32).await?;
+                Self::write_meta(db, current).await?;
+                return Ok((t, current.clone(), true));
             },
             Ok(t) => t
         };
 
-        Ok(table)
+        let schema = table.schema().await?;
+        let has_locations = schema.field_with_name("start_line").is_ok();
+        let dim = match schema.field_with_name("embedings").map(|f| f.data_type().clone()) {
+            Ok(DataType::FixedSizeList(_, n)) => n as usize,
+            _ => 0,
+        };
+
+        let meta = Self::read_meta(db).await?;
+        let has_meta = meta.is_some();
+        let mut stored = match meta {
+            Some(m) => m,
+            // older versions kept no meta, what the table tells and the rest assumed
+            None => Meta {
+                schema_version: if has_locations { SCHEMA_VERSION } else { 1 },
+                dim,
+                ..current.clone()
+            },
+        };
+
+        if stored.schema_version > SCHEMA_VERSION {
+            return Err(refuse(format!(
+                "the index was built by a newer readit (schema version {}), this one knows up to {}",
+                stored.schema_version, SCHEMA_VERSION
+            )));
+        }
+        if !has_locations {
+            let table = Self::migrate_table(db, &table, dim as i32).await?;
+            stored.schema_version = SCHEMA_VERSION;
+            return Ok((table, stored, has_meta));
+        }
+
+        Ok((table, stored, has_meta))
     }
 
-    fn split_string(&self, s: String) -> Vec<String> {
-        let mut r: Vec<String> = Vec::new();
-        let mut sum = 0;
-        let mut rr: Vec<String> = Vec::new();
-        for l in s.split("\n") {
-            if l.len() + sum < MAX_TOKENS{
-                rr.push(l.to_string());
-                sum += l.len();
-            } else {
-                r.push(rr.join("\n").to_string());
-                sum = 0;
-                rr = Vec::new();
+    async fn read_meta(db: &Connection) -> Result<Option<Meta>> {
+        let table = match db.open_table(META_TABLE_NAME).execute().await {
+            Err(_) => return Ok(None),
+            Ok(t) => t,
+        };
+        let results = table.query()
+            .execute()
+            .await?
+            .try_collect::<Vec<RecordBatch>>()
+            .await?
+        ;
+        let mut values: HashMap<String, String> = HashMap::new();
+        for rb in results.iter() {
+            let column = |name: &str| rb.column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .clone()
+            ;
+            let (key, value) = (column("key"), column("value"));
+            for i in 0..rb.num_rows() {
+                values.insert(key.value(i).to_string(), value.value(i).to_string());
+            }
+        }
+        let get = |k: &str| values.get(k).cloned().unwrap_or_default();
+        Ok(Some(Meta {
+            schema_version: get("schema_version").parse().unwrap_or(0),
+            embedding_model: get("embedding_model"),
+            dim: get("dim").parse().unwrap_or(0),
+            analyse_model: get("analyse_model"),
+            prompt_version: get("prompt_version"),
+        }))
+    }
+
+    async fn write_meta(db: &Connection, meta: &Meta) -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("key"         , DataType::Utf8    , false)    ,
+            Field::new("value"       , DataType::Utf8    , false)    ,
+        ]));
+        let rows = meta.rows();
+        let rb = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.0))),
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.1.clone()))),
+            ],
+        )?;
+        db.create_table(META_TABLE_NAME, Box::new(RecordBatchIterator::new(vec![Ok(rb)], schema)))
+            .mode(CreateTableMode::Overwrite)
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// an index built with other models is not used as is. analyses of
+    /// another model or prompt need a new init, vectors of another embedding
+    /// model are computed again from the stored contents when the user agrees
+    async fn check(&mut self, stored: &Meta, current: &Meta) -> Result<()> {
+        if stored.analyse_model != current.analyse_model || stored.prompt_version != current.prompt_version {
+            return Err(refuse(format!(
+                "the index was analysed with {} (prompt version {}), the config says {} (prompt version {}).\nrun \"readit init\" to analyse the project again, or set analyse_model back",
+                stored.analyse_model, stored.prompt_version, current.analyse_model, current.prompt_version
+            )));
+        }
+        if stored.embedding_model != current.embedding_model || stored.dim != current.dim {
+            println!(
+                "the index was embedded with {} (dim {}), the config says {} (dim {}).",
+                stored.embedding_model, stored.dim, current.embedding_model, current.dim
+            );
+            println!("re-embed it now? the analyses are kept, only the vectors are computed again");
+            println!("Yes(default)/No: ");
+
+            let mut y_n = String::new();
+            let _ = io::stdin().read_line(&mut y_n);
+            y_n = y_n.replace("\n", "").replace(" ", "").replace("\r", "");
+            if y_n == "No" || y_n == "no" {
+                return Err(refuse(
+                    "run \"readit init\" to build the index again, or set embedding_model and dim back".to_string()
+                ));
             }
-            
+            self.reembed().await?;
+        }
+        Ok(())
+    }
+
+    /// every row embedded again with the current model and dim
+    async fn reembed(&mut self) -> Result<()> {
+        println!("re-embedding the index");
+        let schema = Self::get_schema(self.dim as i32);
+        let mut tokens = 0;
+        let mut batches = Vec::new();
+        for rb in self.all().await?.iter().filter(|rb| rb.num_rows() > 0) {
+            let contents: Vec<String> = rb.column_by_name("content")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .iter()
+                .map(|c| c.unwrap_or("").to_string())
+                .collect();
+            let (vectors, t) = self.embedding_contents(&contents).await?;
+            tokens += t;
+            let embedding_array: Arc<dyn Array> = Arc::new(self.vectors_array(&vectors));
+            let columns = schema.fields().iter().map(|f| match f.name().as_str() {
+                "embedings" => embedding_array.clone(),
+                name => rb.column_by_name(name).unwrap().clone(),
+            }).collect();
+            batches.push(RecordBatch::try_new(schema.clone(), columns));
+        }
+
+        self.table = if batches.is_empty() {
+            Self::init_table(&self.db, self.dim as i32).await?
+        } else {
+            self.db.create_table(TABLE_NAME, Box::new(RecordBatchIterator::new(batches, schema.clone())))
+                .mode(CreateTableMode::Overwrite)
+                .execute()
+                .await?
         };
-        r
+        println!("re-embedding use tokens: {}", tokens);
+        Ok(())
     }
 
-    async fn embedding_compute(&self, query: String)
-        -> Result<(Float32Array, u32)> 
+    /// tables of older versions miss the location columns,
+    /// copy their rows into the current schema with the new columns null
+    async fn migrate_table(db: &Connection, table: &Table, dim: i32) -> Result<Table> {
+        println!("adding location columns to the index");
+        let schema = Self::get_schema(dim);
+        let batches = table.query()
+            .execute()
+            .await?
+            .try_collect::<Vec<RecordBatch>>()
+            .await?
+        ;
+        if batches.iter().all(|rb| rb.num_rows() == 0) {
+            return Self::init_table(db, dim).await;
+        }
+
+        let batches = batches.iter().map(|rb| {
+            let columns = schema.fields().iter().map(|f| match rb.column_by_name(f.name()) {
+                Some(c) => c.clone(),
+                None => new_null_array(f.data_type(), rb.num_rows()),
+            }).collect();
+            RecordBatch::try_new(schema.clone(), columns)
+        }).collect::<Vec<_>>();
+
+        db.create_table(TABLE_NAME, Box::new(RecordBatchIterator::new(batches, schema.clone())))
+            .mode(CreateTableMode::Overwrite)
+            .execute()
+            .await
+    }
+
+    /// one vector per content, from the cache when it was embedded before
+    async fn embedding_contents(&self, contents: &[String])
+        -> Result<(Vec<Vec<f32>>, u32)>
     {
-        let (embedding, tokens) = match self.client.embedding_compute(
-                Arc::new(StringArray::from_iter_values(once(query.clone())))
-            ).await
-        {
-            Ok((e, t)) => (e, t),
-            Err(e) => {
-                match e {
-                    OpenAIError::ApiError(ref ee) => {
-                        if ee.message.contains(
-                            "This model's maximum context length "
-                        ) {
-                            let string_list = self.split_string(query);
-                            let mut _e_all_builder = PrimitiveBuilder::<Float32Type>::new();
-                            let mut token = 0;
-                            for s in string_list.iter() {
-                                let (e, t) = self.client.embedding_compute(
-                                    Arc::new(StringArray::from_iter_values(once(s.clone())))
-                                ).await.unwrap();
-                                token += t;
-                                for e in e.iter() {
-                                    _e_all_builder.append_value(e.unwrap());
-                                }
-                            };
-                            let _e_all = _e_all_builder.finish();
-                            (pooling::pooling(self.dim, &_e_all), token)
-                        } else {
-                            panic!("embedding error, {:?}", e);
-                        }
-                    },
-                    _ => {
-                        panic!("embedding error, {:?}", e);
-                    }
-                }
-                //println!("embedding error: {:?}", e);
-                //panic!("embedding error, {:?}", e);
+        let mut vectors: Vec<Option<Vec<f32>>> = contents.iter()
+            .map(|c| self.cache.embedding(c))
+            .collect();
+        let missing: Vec<String> = contents.iter()
+            .zip(vectors.iter())
+            .filter(|(_, v)| v.is_none())
+            .map(|(c, _)| c.clone())
+            .collect();
+        if missing.is_empty() {
+            return Ok((vectors.into_iter().map(|v| v.unwrap()).collect(), 0));
+        }
+
+        let (computed, tokens) = self.compute_contents(&missing).await?;
+        let mut computed = computed.into_iter();
+        for (c, v) in contents.iter().zip(vectors.iter_mut()) {
+            if v.is_none() {
+                let e = computed.next().unwrap();
+                self.cache.put_embedding(c, &e);
+                *v = Some(e);
             }
-        };
-        Ok((embedding, tokens))
+        }
+        Ok((vectors.into_iter().map(|v| v.unwrap()).collect(), tokens))
     }
-      
-    pub async fn add_data(&self, data: structs::CodeDescription) -> Result<u32> {
 
-        let schema = Self::get_schema(self.dim as i32);
+    /// one vector per content. contents longer than the model takes are
+    /// split into overlapping chunks, and the vectors of the chunks pooled
+    async fn compute_contents(&self, contents: &[String])
+        -> Result<(Vec<Vec<f32>>, u32)>
+    {
+        // (index of the content, chunk, tokens)
+        let mut chunks: Vec<(usize, String, usize)> = Vec::new();
+        for (i, c) in contents.iter().enumerate() {
+            for (chunk, n) in self.tokenizer.split(c, self.max_tokens, self.overlap) {
+                chunks.push((i, chunk, n));
+            }
+        }
 
-        //let line_number = Int32Array::from(vec![data.line_number]);
-        //let lines = Int32Array::from(vec![data.lines]);
-        let file = StringArray::from_iter_values(vec![ data.file.clone().unwrap(),]);
-        let md5 = StringArray::from_iter_values(vec![ data.md5.clone().unwrap(),]);
-        let code_type = StringArray::from_iter_values(vec![ data.code_type.clone().unwrap(),]);
-        let lang = StringArray::from_iter_values(vec![ data.lang.clone().unwrap(),]);
-        let name = StringArray::from_iter_values(vec![ data.name.clone(),]);
-        let purpose = StringArray::from_iter_values(vec![ data.purpose.clone(),]);
-        
-        let content_string = format!("//file {:} \n//{:} name: {:}\n\n// {:}\n{:}",
+        let mut chunk_vectors: Vec<Vec<f32>> = Vec::new();
+        let mut tokens = 0;
+        let mut start = 0;
+        while start < chunks.len() {
+            let mut end = start;
+            let mut size = 0;
+            while end < chunks.len() && (
+                end == start || (
+                    end - start < MAX_BATCH_ITEMS
+                    && size + chunks[end].2 <= MAX_BATCH_TOKENS
+                )
+            ) {
+                size += chunks[end].2;
+                end += 1;
+            }
+            let (e, t) = self.client.embedding.embedding_compute(
+                Arc::new(StringArray::from_iter_values(chunks[start..end].iter().map(|c| &c.1)))
+            ).await.map_err(embedding_error)?;
+            if e.len() != (end - start) * self.dim {
+                // a model of another size than `dim`, the table can not take its vectors
+                return Err(embedding_error(ProviderError::Other(format!(
+                    "expected {} vectors of {} values, got {} values, is dim right for the embedding model?",
+                    end - start, self.dim, e.len()
+                ))));
+            }
+            chunk_vectors.extend(e.values().chunks(self.dim).map(|v| v.to_vec()));
+            tokens += t;
+            start = end;
+        }
+
+        let vectors = (0..contents.len()).map(|i| {
+            let mut own = chunks.iter()
+                .zip(chunk_vectors.iter())
+                .filter(|(c, _)| c.0 == i)
+                .map(|(_, v)| v.clone())
+                .collect::<Vec<Vec<f32>>>();
+            if own.len() == 1 {
+                return own.pop().unwrap();
+            }
+            let all = Float32Array::from_iter_values(own.concat());
+            pooling::pooling(self.dim, &all).values().to_vec()
+        }).collect();
+
+        Ok((vectors, tokens))
+    }
+
+    /// `:120-168` when the lines of a row are known, `:cell 3-4` in notebooks
+    fn location(file: &str, start_line: Option<usize>, end_line: Option<usize>) -> String {
+        match (start_line, end_line) {
+            (Some(s), Some(e)) if notebook_utils::is_notebook(file) => format!(":cell {}-{}", s, e),
+            (Some(s), Some(e)) => format!(":{}-{}", s, e),
+            _ => "".to_string(),
+        }
+    }
+
+    /// what is embedded and handed to the chat model for one row
+    fn content_string(data: &structs::CodeDescription) -> String {
+        format!("//file {:}{:} \n//{:} name: {:}\n\n// {:}\n{:}",
                 data.file.clone().unwrap(),
+                Self::location(data.file.as_deref().unwrap(), data.start_line, data.end_line),
                 data.code_type.clone().unwrap(),
                 data.name.clone(),
                 data.purpose.clone(),
                 data.source_code.clone()
-        );
+        )
+    }
 
-        let content = StringArray::from_iter_values(vec![content_string.to_string()]);
+    fn vectors_array(&self, vectors: &[Vec<f32>]) -> FixedSizeListArray {
+        let float_builder = Float32Array::builder(self.dim * vectors.len());
+        let mut fixed_size_list_builder = FixedSizeListBuilder::new(float_builder, self.dim as i32);
 
-        let (embedding, tokens) = self.embedding_compute(content_string).await?;
+        for v in vectors.iter() {
+            fixed_size_list_builder.values().append_slice(v);
+            fixed_size_list_builder.append(true);
+        }
 
-        let float_builder = Float32Array::builder(self.dim);
-        let mut fixed_size_list_builder = FixedSizeListBuilder::new(float_builder, self.dim as i32);  // 每个子数组长度为 3
+        fixed_size_list_builder.finish()
+    }
+
+    pub async fn add_data(&self, data: structs::CodeDescription) -> Result<u32> {
+        self.add_data_batch(vec![data]).await
+    }
 
-        for e in embedding.iter() {
-            fixed_size_list_builder.values().append_value(e.unwrap());
+    /// embed all rows with as few requests as possible and write them at once
+    pub async fn add_data_batch(&self, data: Vec<structs::CodeDescription>) -> Result<u32> {
+
+        if data.is_empty() {
+            return Ok(0);
         }
-        fixed_size_list_builder.append(true);
 
-        let embedding_array = fixed_size_list_builder.finish();
+        let schema = Self::get_schema(self.dim as i32);
+
+        let contents: Vec<String> = data.iter().map(Self::content_string).collect();
+
+        let (vectors, tokens) = self.embedding_contents(&contents).await?;
+
+        //let line_number = Int32Array::from(vec![data.line_number]);
+        //let lines = Int32Array::from(vec![data.lines]);
+        let file = StringArray::from_iter_values(data.iter().map(|d| d.file.clone().unwrap()));
+        let md5 = StringArray::from_iter_values(data.iter().map(|d| d.md5.clone().unwrap()));
+        let code_type = StringArray::from_iter_values(data.iter().map(|d| d.code_type.clone().unwrap()));
+        let lang = StringArray::from_iter_values(data.iter().map(|d| d.lang.clone().unwrap()));
+        let name = StringArray::from_iter_values(data.iter().map(|d| d.name.clone()));
+        let purpose = StringArray::from_iter_values(data.iter().map(|d| d.purpose.clone()));
+        let content = StringArray::from_iter_values(contents.iter());
+        let location = |f: fn(&structs::CodeDescription) -> Option<usize>| Int32Array::from(
+            data.iter().map(|d| f(d).map(|v| v as i32)).collect::<Vec<Option<i32>>>()
+        );
+        let start_line = location(|d| d.start_line);
+        let end_line = location(|d| d.end_line);
+        let start_byte = location(|d| d.start_byte);
+        let end_byte = location(|d| d.end_byte);
+
+        let embedding_array = self.vectors_array(&vectors);
 
         let rb = RecordBatch::try_new(
             schema.clone(),
@@ -233,6 +575,10 @@ impl <'a> Embedding <'a> {
                 Arc::new(name       ),
                 Arc::new(purpose    ),
                 Arc::new(content    ),
+                Arc::new(start_line ),
+                Arc::new(end_line   ),
+                Arc::new(start_byte ),
+                Arc::new(end_byte   ),
                 Arc::new(embedding_array  ),
             ],
         )?;
@@ -242,9 +588,20 @@ impl <'a> Embedding <'a> {
         Ok(tokens)
     }
 
+    /// deletes every row of the files, gives how many there were
+    pub async fn delete_files(&self, files: &[String]) -> Result<usize> {
+        let mut rows = 0;
+        for f in files.iter() {
+            let filter = format!("file = '{}'", f.replace('\'', "''"));
+            rows += self.table.count_rows(Some(filter.clone())).await?;
+            self.table.delete(filter.as_str()).await?;
+        }
+        Ok(rows)
+    }
+
     pub async fn delete_file(&self, data: structs::CodeDescription) -> Result<()> {
         self.table.delete(
-            format!("file = '{}'", data.file.clone().unwrap()).as_str()
+            format!("file = '{}'", data.file.clone().unwrap().replace('\'', "''")).as_str()
         ).await?;
         Ok(())
     }
@@ -265,9 +622,9 @@ impl <'a> Embedding <'a> {
 
         let query = Arc::new(StringArray::from_iter_values(once(prompt)));
 
-        let (query_vector, tokens) = self.client.embedding_compute(query)
+        let (query_vector, tokens) = self.client.embedding.embedding_compute(query)
             .await
-            .unwrap()
+            .map_err(embedding_error)?
         ;
         let query_vector = query_vector
             .iter()
@@ -294,31 +651,50 @@ impl <'a> Embedding <'a> {
             .try_collect::<Vec<RecordBatch>>()
             .await?
         ;
-        println!("find {} answers", results.iter().len());
-        let r: Vec<String> = results.iter().map(|rb| {
-            let out = rb.column_by_name("content")
-                .unwrap()
-                .as_any()
-                .downcast_ref::<StringArray>()
-                .unwrap()
-                ;
-            let text = out.iter().next().unwrap().unwrap();
-            let name = rb.column_by_name("name")
-                .unwrap()
-                .as_any()
-                .downcast_ref::<StringArray>()
-                .unwrap()
-                ;
-            //let name = name.iter().next().unwrap().unwrap();
-            //println!("name: {}", name);
-            //println!("text: {}", text);
-            text.to_string()
-        }).collect();
+        let column = |rb: &RecordBatch, name: &str| rb.column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .clone()
+        ;
+        let lines = |rb: &RecordBatch, name: &str| rb.column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap()
+            .clone()
+        ;
+
+        let mut r: Vec<String> = Vec::new();
+        println!("find {} answers", results.iter().map(|rb| rb.num_rows()).sum::<usize>());
+        for rb in results.iter() {
+            let (content, file, code_type, name) = (
+                column(rb, "content"), column(rb, "file"), column(rb, "code_type"), column(rb, "name")
+            );
+            let (start_line, end_line) = (lines(rb, "start_line"), lines(rb, "end_line"));
+            for i in 0..rb.num_rows() {
+                let location = Self::location(
+                    file.value(i),
+                    start_line.is_valid(i).then(|| start_line.value(i) as usize),
+                    end_line.is_valid(i).then(|| end_line.value(i) as usize),
+                );
+                println!(
+                    "    {}{}  {} {}",
+                    file.value(i), location, code_type.value(i), name.value(i)
+                );
+                r.push(content.value(i).to_string());
+            }
+        }
         Ok((r, tokens))
     }
 
+    /// files that failed to analyse count as changed, so they are tried again
     pub async fn is_file_change(&self, file_path: &String, md5: &String) -> Result<bool> {
-        let query = format!("file == \"{}\" and md5 == \"{}\"", file_path, md5);
+        let query = format!(
+            "file == \"{}\" and md5 == \"{}\" and code_type != \"failed\"",
+            file_path, md5
+        );
         let results = self.table.query()
             .select(Select::Columns(vec!["file".to_string(), "md5".to_string()]))
             .only_if(query)
@@ -327,10 +703,129 @@ impl <'a> Embedding <'a> {
             .try_collect::<Vec<RecordBatch>>()
             .await?
         ;
-        Ok(results.len() == 0)
+        Ok(results.is_empty())
+    }
+
+
+    /// every path in the file column
+    pub async fn files(&self) -> Result<Vec<String>> {
+        let results = self.table.query()
+            .select(Select::Columns(vec!["file".to_string()]))
+            .execute()
+            .await?
+            .try_collect::<Vec<RecordBatch>>()
+            .await?
+        ;
+        let mut files: Vec<String> = results.iter()
+            .flat_map(|rb| rb.column_by_name("file")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .iter()
+                .map(|f| f.unwrap().to_string())
+                .collect::<Vec<String>>()
+            )
+            .filter(|f| f != "whole project")
+            .collect();
+        files.sort();
+        files.dedup();
+        Ok(files)
     }
 
+    /// absolute paths under root made relative to it, the content of the
+    /// rows names the file too, so they are embedded again.
+    /// gives how many files were rebased, how many stay absolute and the tokens
+    pub async fn rebase_paths(&self, root: &Path) -> Result<(usize, usize, u32)> {
+        let (mut rebased, mut left, mut tokens) = (0, 0, 0);
+        let schema = Self::get_schema(self.dim as i32);
+        for file in self.files().await? {
+            if !Path::new(&file).is_absolute() {
+                continue;
+            }
+            let relative = match Path::new(&file).strip_prefix(root).ok().and_then(|r| r.components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<&str>>>()
+            ) {
+                Some(r) => r.join("/"),
+                // not under root, or a path that is not utf-8
+                None => {
+                    left += 1;
+                    continue;
+                },
+            };
+
+            let filter = format!("file = '{}'", file.replace('\'', "''"));
+            // init indexed the file again after the move, the old rows are stale
+            if self.table.count_rows(Some(format!("file = '{}'", relative.replace('\'', "''")))).await? > 0 {
+                self.table.delete(filter.as_str()).await?;
+                rebased += 1;
+                continue;
+            }
+            let rows = self.table.query()
+                .only_if(filter.clone())
+                .execute()
+                .await?
+                .try_collect::<Vec<RecordBatch>>()
+                .await?
+            ;
+            let head = format!("//file {}", file);
+            let mut batches = Vec::new();
+            for rb in rows.iter().filter(|rb| rb.num_rows() > 0) {
+                let contents: Vec<String> = rb.column_by_name("content")
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .unwrap()
+                    .iter()
+                    .map(|c| {
+                        let c = c.unwrap_or("");
+                        match c.strip_prefix(&head) {
+                            Some(rest) => format!("//file {}{}", relative, rest),
+                            None => c.to_string(),
+                        }
+                    })
+                    .collect();
+                let (vectors, t) = self.embedding_contents(&contents).await?;
+                tokens += t;
+                let embedding_array: Arc<dyn Array> = Arc::new(self.vectors_array(&vectors));
+                let content_array: Arc<dyn Array> = Arc::new(StringArray::from_iter_values(contents.iter()));
+                // the row of the whole file is named after its path
+                let name_array: Arc<dyn Array> = Arc::new(StringArray::from_iter_values(
+                    rb.column_by_name("name")
+                        .unwrap()
+                        .as_any()
+                        .downcast_ref::<StringArray>()
+                        .unwrap()
+                        .iter()
+                        .map(|n| match n.unwrap_or("") {
+                            n if n == file => relative.as_str(),
+                            n => n,
+                        })
+                ));
+                let file_array: Arc<dyn Array> = Arc::new(StringArray::from_iter_values(
+                    std::iter::repeat_n(&relative, rb.num_rows())
+                ));
+                let columns = schema.fields().iter().map(|f| match f.name().as_str() {
+                    "embedings" => embedding_array.clone(),
+                    "content" => content_array.clone(),
+                    "file" => file_array.clone(),
+                    "name" => name_array.clone(),
+                    name => rb.column_by_name(name).unwrap().clone(),
+                }).collect();
+                batches.push(RecordBatch::try_new(schema.clone(), columns));
+            }
+            // the new rows first, a failure in between leaves the old ones
+            self.table.add(Box::new(RecordBatchIterator::new(batches, schema.clone())))
+                .execute()
+                .await?;
+            self.table.delete(filter.as_str()).await?;
+            rebased += 1;
+        }
+        Ok((rebased, left, tokens))
+    }
 
+    #[allow(dead_code)]
     pub async fn search_other(&self, column: String, value: String) -> Result<Vec<RecordBatch>> {
         let query = format!("{} == \"{}\"", column, value);
         let results = self.table.query()
@@ -345,6 +840,12 @@ impl <'a> Embedding <'a> {
         Ok(results)
     }
 
+    /// files are added concurrently, the last add to finish may leave the
+    /// handle on an older version than the one written
+    pub async fn checkout_latest(&self) -> Result<()> {
+        self.table.checkout_latest().await
+    }
+
     pub async fn all(&self) -> Result<Vec<RecordBatch>> {
         let results = self.table.query()
             .select(Select::All)
@@ -357,8 +858,8 @@ impl <'a> Embedding <'a> {
         Ok(results)
     }
 
+    /// the old summary is replaced only once the new one is embedded
     pub async fn update_summary(&self, language: String) -> u32 {
-        self.table.delete("file = 'whole project'").await.unwrap();
         let all = self.all().await.unwrap();
 
         //let all = self.search_other(
@@ -395,6 +896,7 @@ impl <'a> Embedding <'a> {
             })
             .collect()
         ;
+        all_file_des.retain(|(f, _)| f != "whole project");
         all_file_des.sort_by_key(|x| x.0.clone());
 
         let summary = all_file_des
@@ -405,18 +907,42 @@ impl <'a> Embedding <'a> {
         ;
 
         //println!("summary: {}", summary);
-        let (summary, t) = self.client.summarize(summary, language).await.unwrap();
-        println!("summarize token usege: {}", t);
+        let (summary, t) = match self.client.chat.summarize(summary, language).await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("failed to summarize the project, {}", e);
+                return 0
+            },
+        };
+        println!("summarize token usege: {}", t.total());
         //println!("summary2: {}", summary);
 
-        self.add_data(structs::CodeDescription {
+        // tells the new row from the old one until that is deleted
+        let md5 = format!("{:x}", md5::compute(&summary));
+        let tokens = self.add_data(structs::CodeDescription {
             file: Some("whole project".to_string()),
-            md5: Some("".to_string()),
+            md5: Some(md5.clone()),
             code_type: Some("file".to_string()),
             lang: Some("".to_string()),
             name: "whole project summary".to_string(),
             purpose: summary,
             source_code: "".to_string(),
-        }).await.unwrap()
+            start_line: None,
+            end_line: None,
+            start_byte: None,
+            end_byte: None,
+        }).await;
+        match tokens {
+            Ok(t) => {
+                self.table.delete(
+                    format!("file = 'whole project' and md5 != '{}'", md5).as_str()
+                ).await.unwrap();
+                t
+            },
+            Err(e) => {
+                println!("failed to embed the project summary, {}", e);
+                0
+            },
+        }
     }
 }
@@ -1,31 +1,28 @@
 use std::io;
-use std::fs;
-use std::env;
 use std::path::Path;
-
-use serde_yml;
+use std::sync::Arc;
 
 use crate::file_utils;
 use crate::config;
+use crate::doc_utils;
 use crate::ignore_rules::Ignore;
-use crate::language_extensions::{
-    LanguageExtensions,
-    FileExtensionList,
-    FileExtensionTypeMap
-};
+use crate::usage_utils::Budget;
+use crate::language_extensions::LanguageExtensions;
 
 #[derive(Debug, Clone)]
 pub struct Env {
-    pub home_dir: String,
     pub work_dir: String,
     pub temp_dir: String,   // 项目配置，db等
     pub config: config::Config, // 全局配置
     pub ignore: Ignore,
     pub language_extensions: LanguageExtensions,
+    pub command: String,    // the running subcommand, for the usage ledger
+    pub budget: Arc<Budget>, // shared by every clone, so all tasks spend from it
 }
 
 impl Env {
-    pub fn new(path: Option<String>) -> Self {
+    /// `config_args` are the `-c key=value` arguments, over every config file
+    pub fn new(path: Option<String>, config_args: Vec<String>) -> Self {
 
         let (home_dir_string, home_exist) = file_utils::home_dir();
         if !home_exist {
@@ -52,7 +49,9 @@ impl Env {
             config::Config::init_config(config_file.as_path());
         }
 
-        let mut config = config::Config::new_from_path(config_file.as_path());
+        let mut config = config::Config::layered(
+            config_file.as_path(), temp_dir.join("config.yaml").as_path(), &config_args
+        );
 
         if config.language().is_empty() {
             println!("Please tell me, what language you speak? Default is English.");
@@ -64,39 +63,53 @@ impl Env {
             if language.is_empty() {
                 language = "English".to_string();
             }
-            config.language = Some(language);
-            config.save(config_file.as_path());
+            config.language = Some(language.clone());
+            // only the answer goes to the global file, not the other layers
+            let mut global = config::Config::new_from_path(config_file.as_path());
+            global.language = Some(language);
+            global.save(config_file.as_path());
         }
 
-        
-        let ignore = Ignore::new_from_path(
-            home_dir.join("ignore_rules.yaml").as_path()
+        // a project's own rules replace the global ones
+        let local_or_global = |name: &str| match temp_dir.join(name).exists() {
+            true => temp_dir.join(name),
+            false => home_dir.join(name),
+        };
+        let mut ignore = Ignore::new_from_path(
+            local_or_global("ignore_rules.yaml").as_path()
         );
-        let language_extensions = LanguageExtensions::new_from_path(
-            home_dir.join("language_extensions.yaml").as_path()
+        let mut language_extensions = LanguageExtensions::new_from_path(
+            local_or_global("language_extensions.yaml").as_path()
         );
+        if config.docs() {
+            doc_utils::enable(&mut ignore, &mut language_extensions);
+        }
+
+        let budget = Arc::new(Budget::new(&config, config.max_tokens(), config.max_cost()));
 
         Self {
-            home_dir: home_dir_string,
             work_dir: work_dir_string,
             temp_dir: temp_dir.to_str().unwrap().to_string(),
             config,
             ignore,
             language_extensions,
+            command: "".to_string(),
+            budget,
         }
     }
 
-    /// new project
-    pub fn is_new_project(&self) -> bool {
-        if Path::new(&self.temp_dir).join("db").exists() {
-            false
-        } else {
-            true
-        }
+    /// limits from the command line, over the ones of the config
+    pub fn set_budget(&mut self, max_tokens: Option<u64>, max_cost: Option<f64>) {
+        self.budget = Arc::new(Budget::new(
+            &self.config,
+            max_tokens.unwrap_or(self.config.max_tokens()),
+            max_cost.unwrap_or(self.config.max_cost()),
+        ));
     }
 
-    pub fn home_dir(&self) -> &Path {
-        Path::new(&self.home_dir)
+    /// new project
+    pub fn is_new_project(&self) -> bool {
+        !Path::new(&self.temp_dir).join("db").exists()
     }
 
     pub fn work_dir(&self) -> &Path {
@@ -104,32 +117,40 @@ impl Env {
     }
 
     pub fn openai_key(&self) -> String {
-        match env::var("OPENAI_KEY") {
-            Ok(val) => val,
-            Err(_) => {
-                self.config.openai_key.clone().unwrap_or("".to_string())
-            }
-        }
+        self.config.openai_key()
+    }
+
+    /// whether any configured provider talks to openai
+    pub fn use_openai(&self) -> bool {
+        self.config.cassette() != "replay" && (
+            self.config.chat_provider() == "openai" || self.config.embedding_provider() == "openai"
+        )
     }
 
     pub fn check_openai_key(&self) -> bool {
-        match self.openai_key().as_str() {
-            "" => false,
-            _ => true,
-        }
+        !self.openai_key().is_empty()
+    }
+
+    /// whether any configured provider talks to azure
+    pub fn use_azure(&self) -> bool {
+        self.config.cassette() != "replay" && (
+            self.config.chat_provider() == "azure" || self.config.embedding_provider() == "azure"
+        )
+    }
+
+    pub fn azure_key(&self) -> String {
+        self.config.azure_key.clone().unwrap_or("".to_string())
+    }
+
+    pub fn check_azure(&self) -> bool {
+        !self.azure_key().is_empty() && !self.config.azure_base().is_empty()
     }
 
     pub fn openai_base(&self) -> String {
-        match env::var("OPENAI_BASE") {
-            Ok(val) => val,
-            Err(_) => {
-                if self.config.openai_base().is_empty() {
-                    panic!("openai_base is not set in the environment")
-                } else {
-                    self.config.openai_base()
-                }
-            }
+        if self.config.openai_base().is_empty() {
+            panic!("openai_base is not set in the environment")
         }
+        self.config.openai_base()
     }
 
 }
@@ -1,23 +1,19 @@
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::fs::File;
 use std::env;
 
-use home;
+use ignore::WalkBuilder;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
-use serde::{Deserialize, Serialize};
-use serde_yml;
+use encoding_rs::Encoding;
+use chardetng::EncodingDetector;
 
 use crate::ignore_rules::Ignore;
-use crate::language_extensions::{
-    LanguageExtensions,
-    FileExtensionList,
-    FileExtensionTypeMap
-};
+use crate::language_extensions::LanguageExtensions;
 
 
-fn file_filter(file: &PathBuf, ignore: &Ignore, file_ext_list: &FileExtensionList) -> bool {
+fn file_filter(file: &Path, ignore: &Ignore) -> bool {
     let file_name = file.file_name().unwrap().to_str().unwrap();
     if ignore.directories.contains(&file_name.to_string()) {
         //println!("1, file_name: {:?}", file_name);
@@ -64,56 +60,57 @@ fn file_filter(file: &PathBuf, ignore: &Ignore, file_ext_list: &FileExtensionLis
         //println!("6, file_name: {:?}", file_name);
         return false;
     }
+    true
+}
 
-
-    if file.is_dir() {
-        //println!("7, file_name: {:?}", file_name);
-        return true;
-    }
-    //println!("8, file_name: {:?}", file_name);
-    match file.extension() {
-        None => return false,
-        Some(ext) => {
-            let ext = &ext.to_str().unwrap().to_string();
-            let ext = format!(".{}", ext);
-            //println!("9, file_name: {:?}", ext);
-            if file_ext_list.contains(&ext) {
-                //println!("ext: {:?}", ext);
-                return true;
+/// the `!` patterns of the project's `.readitignore`. what they match is
+/// indexed even when the global rules drop it
+fn include_rules(root: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root);
+    if let Ok(s) = fs::read_to_string(root.join(".readitignore")) {
+        for line in s.lines() {
+            if let Some(pattern) = line.trim().strip_prefix('!') {
+                let _ = builder.add_line(None, pattern);
             }
         }
     }
-    false
-}
-
-
-fn source_language(file: &PathBuf, file_extension_map: &FileExtensionTypeMap) -> String {
-    let ext = file.extension().unwrap().to_str().unwrap();
-    let ext = format!(".{}", ext);
-    match file_extension_map.get(&ext) {
-        None => "unknown".to_string(),
-        Some(lang) => lang.clone()
-    }
+    builder.build().unwrap_or(Gitignore::empty())
 }
 
+/// the source files under path with their language. skips what the global
+/// rules, `.gitignore`, `.git/info/exclude` or `.readitignore` ignore
 pub fn list_path(
     path: &Path,
     file_list: &mut Vec<(PathBuf, String)>,
     ignore: &Ignore,
     language_extensions: &LanguageExtensions,
 ) {
-    match fs::read_dir(path) {
-        Err(e) => println!("Error: {}", e),
-        Ok(paths) => {
-            for p in paths {
-                let p = p.unwrap().path();
-                if !file_filter(&p, ignore, &language_extensions.ext_list) {
+    let includes = include_rules(path);
+    let ignore = ignore.clone();
+    let walker = WalkBuilder::new(path)
+        .hidden(false)
+        .parents(true)
+        .git_ignore(true)
+        .git_exclude(true)
+        .git_global(false)
+        .require_git(false)
+        .add_custom_ignore_filename(".readitignore")
+        .filter_entry(move |e| {
+            let is_dir = e.file_type().is_some_and(|t| t.is_dir());
+            includes.matched_path_or_any_parents(e.path(), is_dir).is_ignore()
+                || file_filter(e.path(), &ignore)
+        })
+        .build();
+
+    for entry in walker {
+        match entry {
+            Err(e) => println!("Error: {}", e),
+            Ok(e) => {
+                if !e.file_type().is_some_and(|t| t.is_file()) {
                     continue;
                 }
-                if p.is_dir() {
-                    list_path(&p, file_list, ignore, language_extensions);
-                } else {
-                    let lang = source_language(&p, &language_extensions.ext_type_map);
+                let p = e.into_path();
+                if let Some(lang) = language_extensions.language(&p) {
                     file_list.push((p, lang));
                 }
             }
@@ -121,6 +118,92 @@ pub fn list_path(
     }
 }
 
+/// the text of a source file, or why it is not indexed:
+/// too large, binary, in an encoding we can not decode, or minified
+pub fn read_source(file: &Path, max_file_size: u64, max_line_length: usize) -> Result<String, String> {
+    let size = fs::metadata(file).map_err(|e| e.to_string())?.len();
+    if size > max_file_size {
+        return Err(format!("larger than {} bytes", max_file_size));
+    }
+    let bytes = fs::read(file).map_err(|e| e.to_string())?;
+
+    let code = match Encoding::for_bom(&bytes) {
+        // utf-16 has zero bytes everywhere, trust the bom before sniffing
+        Some((encoding, bom_len)) => {
+            let (code, had_errors) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+            if had_errors {
+                return Err(format!("not valid {}", encoding.name()));
+            }
+            code.into_owned()
+        },
+        None => {
+            if bytes.iter().take(8000).any(|b| *b == 0) {
+                return Err("binary".to_string());
+            }
+            match String::from_utf8(bytes) {
+                Ok(code) => code,
+                Err(e) => {
+                    let bytes = e.into_bytes();
+                    let mut detector = EncodingDetector::new();
+                    detector.feed(&bytes, true);
+                    let encoding = detector.guess(None, false);
+                    let (code, had_errors) = encoding.decode_without_bom_handling(&bytes);
+                    if had_errors {
+                        return Err(format!("unknown encoding, not valid {}", encoding.name()));
+                    }
+                    code.into_owned()
+                },
+            }
+        },
+    };
+
+    check_source(&code, u64::MAX, max_line_length)?;
+    Ok(code)
+}
+
+/// the limits of `read_source` on a source made from a file, a notebook
+pub fn check_source(code: &str, max_file_size: u64, max_line_length: usize) -> Result<(), String> {
+    if code.len() as u64 > max_file_size {
+        return Err(format!("larger than {} bytes", max_file_size));
+    }
+    // minified or generated, one huge line says nothing to the model
+    let lines = code.lines().filter(|l| !l.trim().is_empty()).count().max(1);
+    if code.trim().chars().count() / lines > max_line_length {
+        return Err(format!("minified, lines longer than {} characters on average", max_line_length));
+    }
+    Ok(())
+}
+
+/// a path of the project as stored in the index, relative to the project
+/// with `/` separators, so a moved or cloned project keeps its index
+pub fn relative_path(work_dir: &Path, file: &Path) -> String {
+    let root = work_dir.canonicalize().unwrap();
+    let file = file.canonicalize().unwrap();
+    let relative = file.strip_prefix(&root).unwrap_or(&file);
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_str().unwrap())
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// where a project was when absolute paths of it were indexed: the directory
+/// most of the paths still exist under once it is replaced by `work_dir`
+pub fn guess_root(work_dir: &Path, files: &[String]) -> Option<PathBuf> {
+    let mut votes: HashMap<PathBuf, usize> = HashMap::new();
+    for f in files.iter().map(Path::new).filter(|f| f.is_absolute()) {
+        for root in f.ancestors().skip(1) {
+            if work_dir.join(f.strip_prefix(root).unwrap()).exists() {
+                *votes.entry(root.to_path_buf()).or_default() += 1;
+            }
+        }
+    }
+    // on a tie the outer directory, which keeps more of the paths
+    votes.into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.as_os_str().len().cmp(&a.0.as_os_str().len())))
+        .map(|(root, _)| root)
+}
+
 pub fn home_dir() -> (String, bool) {
     let _home_dir_p = home::home_dir().unwrap();
     let home_dir = _home_dir_p.as_path().join(".readit");
@@ -149,7 +232,6 @@ pub fn init_home(
             
     }
     if !home_dir.join("language_extensions.yaml").exists() {
-        // TODO
         language_extensions.write_to_file(
             home_dir.join("language_extensions.yaml").as_path()
         );
@@ -166,3 +248,132 @@ pub fn init_workdir() -> String {
     };
     work_dir.to_str().unwrap().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guess_root_of_moved_project() {
+        let dir = tempfile::tempdir().unwrap();
+        let work_dir = dir.path().join("new");
+        fs::create_dir_all(work_dir.join("src")).unwrap();
+        fs::write(work_dir.join("src/main.rs"), "").unwrap();
+        fs::write(work_dir.join("README.md"), "").unwrap();
+
+        let files = vec![
+            "/old/home/project/src/main.rs".to_string(),
+            "/old/home/project/README.md".to_string(),
+            "/old/home/project/deleted.rs".to_string(),
+            "src/main.rs".to_string(),
+        ];
+        assert_eq!(guess_root(&work_dir, &files), Some(PathBuf::from("/old/home/project")));
+    }
+
+    #[test]
+    fn guess_root_without_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(guess_root(dir.path(), &["/old/project/gone.rs".to_string()]), None);
+        assert_eq!(guess_root(dir.path(), &[]), None);
+    }
+
+    /// the files list_path finds under dir, relative and sorted
+    fn listed(dir: &Path) -> Vec<String> {
+        let mut file_list = Vec::new();
+        list_path(dir, &mut file_list, &Ignore::new(), &LanguageExtensions::new());
+        let mut files: Vec<String> = file_list.iter()
+            .map(|(f, _)| relative_path(dir, f))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn list_path_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for f in [
+            "main.py", "gitignored/a.py", "excluded.py", "readitignored.py",
+            "tests/test_main.py", "tests/helpers.py", "test_helpers.py", "build/out.py",
+        ] {
+            fs::create_dir_all(root.join(f).parent().unwrap()).unwrap();
+            fs::write(root.join(f), "print(1)\n").unwrap();
+        }
+        fs::create_dir_all(root.join(".git/info")).unwrap();
+        fs::write(root.join(".git/info/exclude"), "excluded.py\n").unwrap();
+        fs::write(root.join(".gitignore"), "gitignored/\n").unwrap();
+        fs::write(root.join(".readitignore"), "readitignored.py\n!tests/\n!test_helpers.py\n").unwrap();
+
+        // tests/ and test_helpers.py are dropped by the global rules, `!` brings them
+        // back, build/ is not named in .readitignore and stays out
+        assert_eq!(listed(root), vec![
+            "main.py", "test_helpers.py", "tests/helpers.py", "tests/test_main.py",
+        ]);
+    }
+
+    #[test]
+    fn include_rules_only_take_bang_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".readitignore"), "generated/\n!tests/\n  !keep.py\n").unwrap();
+        let includes = include_rules(dir.path());
+        assert!(includes.matched(dir.path().join("tests"), true).is_ignore());
+        assert!(includes.matched(dir.path().join("keep.py"), false).is_ignore());
+        assert!(!includes.matched(dir.path().join("generated"), true).is_ignore());
+
+        assert!(include_rules(&dir.path().join("nothing")).is_empty());
+    }
+
+    #[test]
+    fn read_source_encodings() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.py");
+
+        fs::write(&file, "print('hi')\n").unwrap();
+        assert_eq!(read_source(&file, 1000, 500).unwrap(), "print('hi')\n");
+
+        // utf-16 with a bom is full of zero bytes, but not binary
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("x = 'é'\n".encode_utf16().flat_map(|u| u.to_le_bytes()));
+        fs::write(&file, &utf16).unwrap();
+        assert_eq!(read_source(&file, 1000, 500).unwrap(), "x = 'é'\n");
+
+        // latin-1, not valid utf-8
+        let latin1: Vec<u8> = "# le café du marché, à côté de l'école\nprint('déjà')\n"
+            .chars()
+            .map(|c| c as u8)
+            .collect();
+        fs::write(&file, &latin1).unwrap();
+        assert_eq!(
+            read_source(&file, 1000, 500).unwrap(),
+            "# le café du marché, à côté de l'école\nprint('déjà')\n"
+        );
+    }
+
+    #[test]
+    fn read_source_skips() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.js");
+
+        fs::write(&file, b"\x7fELF\x00\x01\x02").unwrap();
+        assert_eq!(read_source(&file, 1000, 500), Err("binary".to_string()));
+
+        fs::write(&file, "x".repeat(2000)).unwrap();
+        assert_eq!(read_source(&file, 1000, 5000), Err("larger than 1000 bytes".to_string()));
+
+        // two lines of 300 characters
+        fs::write(&file, format!("{}\n{}\n", "a".repeat(300), "b".repeat(300))).unwrap();
+        assert!(read_source(&file, 1000, 500).is_ok());
+        assert_eq!(
+            read_source(&file, 1000, 200),
+            Err("minified, lines longer than 200 characters on average".to_string())
+        );
+    }
+
+    #[test]
+    fn relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/c.rs"), "").unwrap();
+        assert_eq!(relative_path(dir.path(), &dir.path().join("a/b/c.rs")), "a/b/c.rs");
+    }
+}
@@ -1,9 +1,8 @@
 use std::fs;
 use std::path::Path;
 use serde::{Deserialize, Serialize};
-use serde_yml;
 
-const IGNORE_RULES: &str = &"directories:
+const IGNORE_RULES: &str = "directories:
   - .git
   - .github
   - .vscode
@@ -19,21 +18,20 @@ const IGNORE_RULES: &str = &"directories:
   - bench
   - dockerfiles
   - target
-directory_prefix:
   - test
+  - tests
   - Test
+  - Tests
+  - __tests__
+directory_prefix: []
 directory_posfix:
-  - Test
-  - test
   - .egg-info
 files:
   - README.md
   - LICENSE
   - requirements.txt
   - setup.py
-  - Dockerfile
   - NOTICE
-  - Makefile
   - Cargo.toml
   - Cargo.lock
   - .gitignore
@@ -41,11 +39,16 @@ files:
   - .gitattributes
   - .editorconfig
 file_prefix:
-  - test
-  - Test
+  - test_
 file_posfix:
-  - Test
-  - test
+  - _test.py
+  - _test.go
+  - Test.java
+  - Tests.java
+  - .test.js
+  - .test.ts
+  - .spec.js
+  - .spec.ts
   - .pyc
   - .pyo
   - .swp
@@ -110,7 +113,6 @@ file_posfix:
   - .tex
   - .bib
   - .bibtex
-  - .Dockerfile
 ";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -0,0 +1,216 @@
+use std::future::Future;
+
+use serde::de::DeserializeOwned;
+use serde_json::{self, json, Value};
+
+use crate::{prompt_utils, structs};
+
+use crate::providers::ProviderError;
+
+/// how many times a model is asked again after an answer that does not parse
+pub const JSON_RETRIES: u32 = 2;
+
+/// the json schema of `structs::GPTResponse`, in the subset strict mode takes:
+/// every property required, no additional properties
+pub fn analyse_schema() -> Value {
+    let item = json!({
+        "type": "object",
+        "properties": {
+            "name": {"type": "string"},
+            "source_code": {"type": "string"},
+            "purpose": {"type": "string"},
+        },
+        "required": ["name", "source_code", "purpose"],
+        "additionalProperties": false,
+    });
+    json!({
+        "type": "object",
+        "properties": {
+            "purpose": {"type": "string"},
+            "classes": {"type": "array", "items": item},
+            "functions": {"type": "array", "items": item},
+        },
+        "required": ["purpose", "classes", "functions"],
+        "additionalProperties": false,
+    })
+}
+
+/// the json schema of `structs::GPTSymbolsResponse`
+pub fn symbols_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "purpose": {"type": "string"},
+            "symbols": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "id": {"type": "integer"},
+                        "purpose": {"type": "string"},
+                    },
+                    "required": ["id", "purpose"],
+                    "additionalProperties": false,
+                },
+            },
+        },
+        "required": ["purpose", "symbols"],
+        "additionalProperties": false,
+    })
+}
+
+/// best effort fix of an almost valid json object from a model:
+/// text around it and ``` fences, trailing commas, and an answer cut
+/// off in the middle, which is trimmed back to its last complete value
+pub fn repair(text: &str) -> String {
+    let start = match text.find('{') {
+        None => return text.to_string(),
+        Some(s) => s,
+    };
+
+    let mut out = String::new();
+    let mut stack: Vec<char> = Vec::new();
+    // the output and the open containers after the last complete value
+    let mut safe: (usize, Vec<char>) = (0, Vec::new());
+    let mut in_string = false;
+    let mut is_key = false;
+    let mut escape = false;
+    let mut last = ' ';
+
+    for c in text[start..].chars() {
+        if in_string {
+            out.push(c);
+            if escape {
+                escape = false;
+            } else if c == '\\' {
+                escape = true;
+            } else if c == '"' {
+                in_string = false;
+                last = '"';
+                if !is_key {
+                    safe = (out.len(), stack.clone());
+                }
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                is_key = stack.last() == Some(&'}') && (last == '{' || last == ',');
+                in_string = true;
+                out.push(c);
+            },
+            '{' | '[' => {
+                stack.push(if c == '{' { '}' } else { ']' });
+                out.push(c);
+                last = c;
+                safe = (out.len(), stack.clone());
+            },
+            '}' | ']' => {
+                let trimmed = out.trim_end().trim_end_matches(',').len();
+                out.truncate(trimmed);
+                out.push(stack.pop().unwrap_or(c));
+                last = c;
+                safe = (out.len(), stack.clone());
+                if stack.is_empty() {
+                    return out;
+                }
+            },
+            ',' => {
+                safe = (out.trim_end().len(), stack.clone());
+                out.push(c);
+                last = c;
+            },
+            c if c.is_whitespace() => out.push(c),
+            _ => {
+                out.push(c);
+                last = c;
+            },
+        }
+    }
+
+    // cut off, close what is still open
+    let (len, mut open) = safe;
+    out.truncate(len);
+    let trimmed = out.trim_end().trim_end_matches(',').len();
+    out.truncate(trimmed);
+    while let Some(close) = open.pop() {
+        out.push(close);
+    }
+    out
+}
+
+/// parse the answer of a model, repairing it when it is not valid as is
+pub fn parse<T: DeserializeOwned>(text: &str) -> Result<T, serde_json::Error> {
+    match serde_json::from_str(text.trim()) {
+        Ok(t) => Ok(t),
+        Err(e) => serde_json::from_str(&repair(text)).map_err(|_| e),
+    }
+}
+
+/// calls `complete` with the prompt until the answer parses; after a bad
+/// answer the model gets it back with the parse error, at most `retries` times
+pub async fn complete_json<T, F, Fut>(prompt: String, retries: u32, complete: F)
+    -> Result<(T, structs::Usage), ProviderError>
+where
+    T: DeserializeOwned,
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<(String, structs::Usage), ProviderError>>,
+{
+    let mut tokens = structs::Usage::default();
+    let mut request = prompt.clone();
+    let mut n = 0;
+    loop {
+        let (text, t) = complete(request).await?;
+        tokens += t;
+        match parse::<T>(&text) {
+            Ok(r) => return Ok((r, tokens)),
+            Err(e) => {
+                if n >= retries {
+                    return Err(ProviderError::InvalidJson(format!(
+                        "{} after {} tries", e, n + 1
+                    ), tokens));
+                }
+                n += 1;
+                request = prompt_utils::fix_json_prompt(prompt.clone(), text, e.to_string());
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repair_strips_fences_and_trailing_commas() {
+        let text = "here it is:\n```json\n{\"purpose\": \"a\", \"symbols\": [1, 2,],}\n```";
+        assert_eq!(repair(text), "{\"purpose\": \"a\", \"symbols\": [1, 2]}");
+    }
+
+    #[test]
+    fn repair_closes_a_cut_off_answer() {
+        // an unfinished value is dropped, a complete one kept,
+        // a number at the end may have been cut off too
+        assert_eq!(repair("{\"a\": [1, 2, 3"), "{\"a\": [1, 2]}");
+        assert_eq!(repair("{\"a\": \"x\", \"b\": \"unfini"), "{\"a\": \"x\"}");
+        assert_eq!(repair("{\"a\": {\"b\": \"x\"}, \"c"), "{\"a\": {\"b\": \"x\"}}");
+    }
+
+    #[test]
+    fn repair_keeps_braces_inside_strings() {
+        let text = "{\"code\": \"fn main() { \\\"}\\\" }\"} trailing";
+        assert_eq!(repair(text), "{\"code\": \"fn main() { \\\"}\\\" }\"}");
+    }
+
+    #[test]
+    fn repair_without_object() {
+        assert_eq!(repair("no json here"), "no json here");
+    }
+
+    #[test]
+    fn parse_repairs() {
+        let value: serde_json::Value = parse("```json\n{\"a\": [1,],}\n```").unwrap();
+        assert_eq!(value, serde_json::json!({"a": [1]}));
+        assert!(parse::<serde_json::Value>("nothing").is_err());
+    }
+}
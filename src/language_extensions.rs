@@ -1,12 +1,13 @@
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
-use serde::{Deserialize, Serialize};
-use serde_yml;
 
-const LANGUAGE_EXTENSIONS: &str = &"
+const LANGUAGE_EXTENSIONS: &str = "
 Python:
   - \".py\"
+  - SConstruct
+  - SConscript
 Java:
   - \".java\"
 JavaScript:
@@ -26,6 +27,12 @@ C#:
 Ruby:
   - \".rb\"
   - \".rbw\"
+  - Rakefile
+  - Gemfile
+  - Guardfile
+  - Vagrantfile
+  - Podfile
+  - Fastfile
 Go:
   - \".go\"
 Swift:
@@ -82,6 +89,7 @@ Groovy:
   - \".grt\"
   - \".gtpl\"
   - \".gsp\"
+  - Jenkinsfile
 Objective-C:
   - \".m\"
   - \".mm\"
@@ -112,16 +120,85 @@ SQL:
   - \".sql\"
 XML:
   - \".xml\"
+Jupyter Notebook:
+  - \".ipynb\"
+Dockerfile:
+  - \".dockerfile\"
+  - \".Dockerfile\"
+  - Dockerfile
+  - Containerfile
+Makefile:
+  - \".mk\"
+  - Makefile
+  - GNUmakefile
+  - makefile
 ";
 
 
 pub type FileExtensionList = Vec<String>;
 pub type FileExtensionTypeMap = HashMap<String, String>;
+pub type FileNameTypeMap = HashMap<String, String>;
+
+/// the language of the program a shebang runs, `python3.11` is Python
+fn interpreter_language(name: &str) -> Option<&'static str> {
+    let name = name.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    let lang = match name.to_lowercase().as_str() {
+        "python" | "pypy" => "Python",
+        "sh" | "bash" | "zsh" | "ksh" | "dash" | "ash" | "shell" => "Shell",
+        "ruby" | "jruby" => "Ruby",
+        "node" | "nodejs" | "bun" | "js" => "JavaScript",
+        "deno" | "ts-node" | "ts" => "TypeScript",
+        "perl" => "Perl",
+        "php" => "PHP",
+        "lua" | "luajit" => "Lua",
+        "rscript" | "r" => "R",
+        "julia" => "Julia",
+        "groovy" => "Groovy",
+        "scala" => "Scala",
+        "make" => "Makefile",
+        "dockerfile" => "Dockerfile",
+        _ => return None,
+    };
+    Some(lang)
+}
+
+/// the program of a `#!` line, `#!/usr/bin/env -S python3 -u` runs python3
+fn shebang(line: &str) -> Option<&str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let program = words.next()?.rsplit('/').next()?;
+    if program != "env" {
+        return Some(program);
+    }
+    words.find(|w| !w.starts_with('-') && !w.contains('='))
+}
+
+/// the file type of a vim (`vim: set ft=ruby:`) or emacs (`-*- mode: ruby -*-`) modeline
+fn modeline(line: &str) -> Option<String> {
+    if let Some(start) = line.find("-*-") {
+        let rest = &line[start + 3..];
+        let vars = &rest[..rest.find("-*-")?];
+        let mode = match vars.find("mode:") {
+            Some(m) => vars[m + 5..].split(';').next()?,
+            None if !vars.contains(':') => vars,
+            None => return None,
+        };
+        return Some(mode.trim().to_string()).filter(|m| !m.is_empty());
+    }
+    let start = ["vim:", "vi:", "ex:"].iter().find_map(|m| line.find(m).map(|i| i + m.len()))?;
+    line[start..]
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .find_map(|o| o.strip_prefix("ft=")
+            .or(o.strip_prefix("filetype="))
+            .or(o.strip_prefix("syntax="))
+        )
+        .map(|ft| ft.to_string())
+}
 
 #[derive(Debug, Clone)]
 pub struct LanguageExtensions {
     pub ext_list: FileExtensionList,
     pub ext_type_map: FileExtensionTypeMap,
+    pub file_name_map: FileNameTypeMap,     // whole file names, `Dockerfile`
 }
 
 impl LanguageExtensions  {
@@ -129,41 +206,166 @@ impl LanguageExtensions  {
         Self::from_str(LANGUAGE_EXTENSIONS)
     }
 
+    /// entries starting with a dot are extensions, the others whole file names
     fn from_str(s: &str) -> Self {
         let _file_extension: HashMap<String, Vec<String>> 
             = serde_yml::from_str(s).unwrap();
 
         let mut file_extension_map: FileExtensionTypeMap = HashMap::new();
+        let mut file_name_map: FileNameTypeMap = HashMap::new();
         for (k, v) in _file_extension.iter() {
             for i in v.iter() {
-                file_extension_map.insert(i.clone(), k.clone());
+                if i.starts_with('.') {
+                    file_extension_map.insert(i.clone(), k.clone());
+                } else {
+                    file_name_map.insert(i.clone(), k.clone());
+                }
             }
         };
         Self {
             ext_list: file_extension_map.clone().into_keys().collect::<FileExtensionList>(),
-            ext_type_map: file_extension_map
+            ext_type_map: file_extension_map,
+            file_name_map,
         }
     }
 
+    /// older versions wrote an empty file, the defaults are used then
     pub fn new_from_path(path: &Path) -> Self {
         let s = fs::read_to_string(path).unwrap();
-        Self::from_str(&s)
+        let language_extensions = Self::from_str(&s);
+        if language_extensions.ext_type_map.is_empty() && language_extensions.file_name_map.is_empty() {
+            return Self::new();
+        }
+        language_extensions
     }
     
     pub fn write_to_file(&self, path: &Path) {
-        let mut o: HashMap<String, Vec<String>> = HashMap::new();
-        self.ext_type_map.values().map(|x| {
-            let v = self.ext_type_map.get(x).unwrap().clone();
-            o
-                .entry(x.clone())
-                .and_modify(|e|{
-                    e.push(v.clone());
-                })
-                .or_insert(vec![v])
-            ;
-        });
+        let mut o: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (k, lang) in self.ext_type_map.iter().chain(self.file_name_map.iter()) {
+            o.entry(lang.clone()).or_default().push(k.clone());
+        }
+        for v in o.values_mut() {
+            v.sort();
+        }
         let ignore_string = serde_yml::to_string(&o).unwrap();
         fs::write(path, ignore_string).unwrap();
     }
 
+    /// a language by a name of it in any case, `ruby` is Ruby
+    fn named(&self, name: &str) -> Option<String> {
+        if let Some(lang) = interpreter_language(name) {
+            return Some(lang.to_string());
+        }
+        self.ext_type_map.values()
+            .chain(self.file_name_map.values())
+            .find(|lang| lang.eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
+    /// the language of a file without an extension, from its `#!` line
+    /// or a vim or emacs modeline in its first or last lines
+    fn sniff(&self, file: &Path) -> Option<String> {
+        let mut f = File::open(file).ok()?;
+        let mut head = vec![0; 8192];
+        let n = f.read(&mut head).ok()?;
+        head.truncate(n);
+        if head.contains(&0) {
+            return None;
+        }
+        let head = String::from_utf8_lossy(&head).to_string();
+        if let Some(lang) = head.lines().next().and_then(shebang).and_then(interpreter_language) {
+            return Some(lang.to_string());
+        }
+
+        let mut tail = String::new();
+        let len = f.metadata().ok()?.len();
+        if len > 8192 {
+            let mut buf = Vec::new();
+            f.seek(SeekFrom::Start(len.saturating_sub(1024))).ok()?;
+            f.read_to_end(&mut buf).ok()?;
+            tail = String::from_utf8_lossy(&buf).to_string();
+        }
+        let last: Vec<&str> = if tail.is_empty() { head.lines().collect() } else { tail.lines().collect() };
+        head.lines().take(5)
+            .chain(last.iter().rev().take(5).copied())
+            .find_map(modeline)
+            .and_then(|m| self.named(&m))
+    }
+
+    /// the language of a file, by its name, its extension, or for files
+    /// without an extension its shebang or modeline. None when unknown
+    pub fn language(&self, file: &Path) -> Option<String> {
+        let file_name = file.file_name()?.to_str()?;
+        if let Some(lang) = self.file_name_map.get(file_name) {
+            return Some(lang.clone());
+        }
+        match file.extension() {
+            Some(ext) => self.ext_type_map.get(&format!(".{}", ext.to_str()?)).cloned(),
+            None => self.sniff(file),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shebang_program() {
+        assert_eq!(shebang("#!/bin/sh"), Some("sh"));
+        assert_eq!(shebang("#!/usr/bin/python3.11 -u"), Some("python3.11"));
+        assert_eq!(shebang("#!/usr/bin/env node"), Some("node"));
+        assert_eq!(shebang("#!/usr/bin/env -S PYTHONPATH=. python3 -u"), Some("python3"));
+        assert_eq!(shebang("# not a shebang"), None);
+        assert_eq!(shebang("#!"), None);
+    }
+
+    #[test]
+    fn interpreter_languages() {
+        assert_eq!(interpreter_language("python3.11"), Some("Python"));
+        assert_eq!(interpreter_language("bash"), Some("Shell"));
+        assert_eq!(interpreter_language("deno"), Some("TypeScript"));
+        assert_eq!(interpreter_language("unknown"), None);
+    }
+
+    #[test]
+    fn vim_modeline() {
+        assert_eq!(modeline("# vim: ft=ruby"), Some("ruby".to_string()));
+        assert_eq!(modeline("// vim: set filetype=go ts=4:"), Some("go".to_string()));
+        assert_eq!(modeline("/* vi:syntax=c */"), Some("c".to_string()));
+        assert_eq!(modeline("# vim: ts=4"), None);
+    }
+
+    #[test]
+    fn emacs_modeline() {
+        assert_eq!(modeline("# -*- mode: ruby; coding: utf-8 -*-"), Some("ruby".to_string()));
+        assert_eq!(modeline(";; -*- lisp -*-"), Some("lisp".to_string()));
+        assert_eq!(modeline("# -*- coding: utf-8 -*-"), None);
+        assert_eq!(modeline("# plain comment"), None);
+    }
+
+    #[test]
+    fn language_of_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let language_extensions = LanguageExtensions::new();
+        let write = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            path
+        };
+
+        assert_eq!(language_extensions.language(&write("main.rs", "")), Some("Rust".to_string()));
+        assert_eq!(language_extensions.language(&write("Dockerfile", "")), Some("Dockerfile".to_string()));
+        assert_eq!(
+            language_extensions.language(&write("run", "#!/usr/bin/env python3\nprint(1)\n")),
+            Some("Python".to_string())
+        );
+        assert_eq!(
+            language_extensions.language(&write("build", "task :default\n\n# vim: ft=ruby\n")),
+            Some("Ruby".to_string())
+        );
+        assert_eq!(language_extensions.language(&write("NOTES", "just text\n")), None);
+        assert_eq!(language_extensions.language(&write("blob", "#!/bin/sh\0")), None);
+    }
 }
@@ -0,0 +1,248 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use serde_json::{self, json, Value};
+
+use crate::{json_utils, prompt_string, prompt_utils, structs};
+
+use crate::env;
+
+use crate::providers::{
+    self,
+    AskStream,
+    ChatProvider,
+    ProviderError,
+};
+
+/// talks to the native api of a llama.cpp server, `/completion`
+///
+/// the server runs a single model, so chat_model and analyse_model are not used
+pub struct LlamaCpp {
+    client: reqwest::Client,
+    base: String,
+    json_schema: bool,
+}
+
+impl LlamaCpp {
+    pub fn new(env: &env::Env) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base: env.config.llamacpp_base(),
+            json_schema: env.config.json_schema(),
+        }
+    }
+
+    /// the answer is held to `json_schema` when there is one
+    fn request(&self, system: &str, prompt: String, stream: bool, json_schema: Option<Value>) -> Value {
+        let mut body = json!({
+            "prompt": format!("{}\n\n{}\n\n", system, prompt),
+            "stream": stream,
+            "n_predict": -1,
+            "cache_prompt": true,
+        });
+        if let Some(json_schema) = json_schema {
+            body["json_schema"] = json_schema;
+        }
+        body
+    }
+
+    /// the whole answer and the tokens it used
+    async fn complete(&self, system: &str, prompt: String, json_schema: Option<Value>)
+        -> Result<(String, structs::Usage), ProviderError>
+    {
+        let body = self.request(system, prompt, false, json_schema);
+        let response = providers::post_json(
+            &self.client, format!("{}/completion", self.base), &body
+        ).await?;
+        let response: Value = response.json()
+            .await
+            .map_err(|e| ProviderError::Other(format!("llama.cpp: {}", e)))?;
+
+        let text = response["content"].as_str().unwrap_or("").to_string();
+        let tokens = structs::Usage {
+            prompt_tokens: response["tokens_evaluated"].as_u64().unwrap_or(0) as u32,
+            completion_tokens: response["tokens_predicted"].as_u64().unwrap_or(0) as u32,
+        };
+        Ok((text, tokens))
+    }
+
+    /// llama.cpp streams server-sent events, `data: {...}`
+    async fn complete_stream(&self, system: &str, prompt: String)
+        -> Result<AskStream, ProviderError>
+    {
+        let body = self.request(system, prompt, true, None);
+        let response = providers::post_json(
+            &self.client, format!("{}/completion", self.base), &body
+        ).await?;
+
+        Ok(Box::pin(providers::response_lines(response).map(|line| {
+            let line = line?;
+            let data = match line.strip_prefix("data:") {
+                None => return Ok("".to_string()),
+                Some(d) => d.trim(),
+            };
+            let frame: Value = serde_json::from_str(data)
+                .map_err(|e| ProviderError::Other(format!("llama.cpp: {}", e)))?;
+            if !frame["error"].is_null() {
+                return Err(ProviderError::Other(format!("llama.cpp: {}", frame["error"])));
+            }
+            Ok(frame["content"].as_str().unwrap_or("").to_string())
+        })))
+    }
+}
+
+#[async_trait]
+impl ChatProvider for LlamaCpp {
+
+    async fn analyse_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<(structs::GPTResponse, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::analyse_source_file_prompt(
+            programming_lang, code_string, language
+        );
+        let json_schema = if self.json_schema {
+            json_utils::analyse_schema()
+        } else {
+            json!({"type": "object"})
+        };
+        json_utils::complete_json(prompt, json_utils::JSON_RETRIES, |prompt| self.complete(
+            prompt_string::ANALYSE_SOURCE_FILE_SYSTEM, prompt, Some(json_schema.clone())
+        )).await
+    }
+
+    async fn analyse_symbols(&self,
+        code_string: String, programming_lang: String, symbols: Vec<String>, language: String
+    ) -> Result<(structs::GPTSymbolsResponse, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::analyse_symbols_prompt(
+            programming_lang, code_string, symbols, language
+        );
+        let json_schema = if self.json_schema {
+            json_utils::symbols_schema()
+        } else {
+            json!({"type": "object"})
+        };
+        json_utils::complete_json(prompt, json_utils::JSON_RETRIES, |prompt| self.complete(
+            prompt_string::ANALYSE_SOURCE_FILE_SYSTEM, prompt, Some(json_schema.clone())
+        )).await
+    }
+
+    async fn split_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<structs::GPTCodeSplitResponse, ProviderError>
+    {
+        let prompt = prompt_utils::split_source_file_prompt(
+            programming_lang, code_string, language
+        );
+        let (text, _) = self.complete(
+            prompt_string::SPLIT_SOURCE_FILE_SYSTEM, prompt, Some(json!({"type": "object"}))
+        ).await?;
+
+        json_utils::parse(&text).map_err(|e| ProviderError::InvalidJson(e.to_string(), structs::Usage::default()))
+    }
+
+    async fn ask(&self,
+        query: String, code_list: Vec<String>, language: String
+    ) -> Result<AskStream, ProviderError>
+    {
+        let prompt = prompt_utils::ask_prompt(
+            query, code_list, language
+        );
+        self.complete_stream(prompt_string::ASSISTANT_SYSTEM, prompt).await
+    }
+
+    async fn chat(&self, message: String) -> Result<AskStream, ProviderError> {
+        self.complete_stream(prompt_string::ASSISTANT_SYSTEM, message).await
+    }
+
+    async fn summarize(&self, query: String, language: String)
+        -> Result<(String, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::summarize_prompt(
+            query, language
+        );
+        let (text, tokens) = self.complete(
+            prompt_string::ASSISTANT_SYSTEM, prompt, None
+        ).await?;
+        let text = html_escape::decode_html_entities(&text).to_string();
+        Ok((text, tokens))
+    }
+
+    async fn merge_purposes(&self,
+        parts: Vec<String>, programming_lang: String, language: String
+    ) -> Result<(String, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::merge_purposes_prompt(
+            programming_lang, parts, language
+        );
+        let (text, tokens) = self.complete(
+            prompt_string::ASSISTANT_SYSTEM, prompt, None
+        ).await?;
+        let text = html_escape::decode_html_entities(&text).to_string();
+        Ok((text, tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn llamacpp(base: String) -> LlamaCpp {
+        LlamaCpp {
+            client: reqwest::Client::new(),
+            base,
+            json_schema: true,
+        }
+    }
+
+    async fn collect(stream: AskStream) -> Vec<Result<String, ProviderError>> {
+        stream.collect().await
+    }
+
+    #[tokio::test]
+    async fn stream_sse_frames() {
+        let (base, server) = providers::stand_in_server(200, "text/event-stream", vec![
+            "data: {\"content\":\"Hel\",\"stop\":false}\n\ndata: {\"cont",
+            "ent\":\"lo\",\"stop\":false}\n\n",
+            ": keep-alive\n\ndata: {\"content\":\"\",\"stop\":true,\"tokens_predicted\":2}\n\n",
+        ]);
+        let text: String = collect(llamacpp(base).complete_stream("system", "hi".to_string()).await.unwrap())
+            .await
+            .into_iter()
+            .map(|piece| piece.unwrap())
+            .collect();
+        assert_eq!(text, "Hello");
+
+        let request = server.join().unwrap();
+        assert_eq!(request["stream"], true);
+        assert_eq!(request["prompt"], "system\n\nhi\n\n");
+        assert!(request.get("json_schema").is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_error_frame() {
+        let (base, server) = providers::stand_in_server(200, "text/event-stream", vec![
+            "data: {\"content\":\"a\"}\n\ndata: {\"error\":{\"code\":500,\"message\":\"slot unavailable\"}}\n\n",
+        ]);
+        let pieces = collect(llamacpp(base).complete_stream("system", "hi".to_string()).await.unwrap()).await;
+        assert_eq!(pieces.iter().filter_map(|p| p.as_ref().ok()).cloned().collect::<String>(), "a");
+        assert!(pieces.iter().any(|p| matches!(p, Err(ProviderError::Other(e)) if e.contains("slot unavailable"))));
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn complete_counts_tokens() {
+        let (base, server) = providers::stand_in_server(200, "application/json", vec![
+            "{\"content\":\"{}\",\"tokens_evaluated\":30,",
+            "\"tokens_predicted\":3}",
+        ]);
+        let (text, tokens) = llamacpp(base)
+            .complete("system", "code".to_string(), Some(json!({"type": "object"})))
+            .await
+            .unwrap();
+        assert_eq!(text, "{}");
+        assert_eq!((tokens.prompt_tokens, tokens.completion_tokens), (30, 3));
+        assert_eq!(server.join().unwrap()["json_schema"], json!({"type": "object"}));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::array::{Array, Float32Builder};
+use arrow_array::Float32Array;
+
+use async_trait::async_trait;
+
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config as BertConfig, DTYPE};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+use serde::Deserialize;
+
+use crate::env;
+use crate::tokenizer_utils::TokenCounter;
+
+use crate::providers::{
+    self,
+    EmbeddingProvider,
+    ProviderError,
+};
+
+/// sentence-transformer (BERT family) model running on the CPU,
+/// loaded from a directory holding `config.json`, `tokenizer.json`
+/// and `model.safetensors`. clones share the model
+#[derive(Clone)]
+pub struct LocalEmbedding {
+    model: Arc<BertModel>,
+    tokenizer: Arc<Tokenizer>,
+    device: Device,
+}
+
+#[derive(Deserialize)]
+struct ModelSizes {
+    hidden_size: usize,
+    max_position_embeddings: usize,
+}
+
+/// the sizes of the model candle keeps to itself
+fn model_sizes(model_path: &Path) -> Result<ModelSizes, ProviderError> {
+    let config_string = fs::read_to_string(model_path.join("config.json"))
+        .map_err(|e| ProviderError::Other(format!("{:?}: {}", model_path.join("config.json"), e)))?;
+    serde_json::from_str(&config_string)
+        .map_err(|e| ProviderError::Other(format!("config.json: {}", e)))
+}
+
+/// counts tokens with the local model's tokenizer, and gives the most tokens
+/// one input can have before the model cuts it off
+pub fn token_counter(env: &env::Env) -> Result<(TokenCounter, usize), ProviderError> {
+    let model_path = env.config.local_model_path();
+    let model_path = Path::new(&model_path);
+    let tokenizer = Tokenizer::from_file(model_path.join("tokenizer.json"))
+        .map_err(|e| ProviderError::Other(format!("tokenizer.json: {}", e)))?;
+    // [CLS] and [SEP] take two of the positions
+    let max_tokens = model_sizes(model_path)?.max_position_embeddings.saturating_sub(2);
+    Ok((TokenCounter::local(tokenizer), max_tokens))
+}
+
+fn candle_error(e: candle_core::Error) -> ProviderError {
+    ProviderError::Other(format!("local embedding error: {}", e))
+}
+
+impl LocalEmbedding {
+    pub fn new(env: &env::Env) -> Result<Self, ProviderError> {
+        let model_path = env.config.local_model_path();
+        if model_path.is_empty() {
+            return Err(ProviderError::Other(
+                "local_model_path is not set in config.yaml".to_string()
+            ));
+        }
+        let model_path = Path::new(&model_path);
+        let device = Device::Cpu;
+
+        let config_string = fs::read_to_string(model_path.join("config.json"))
+            .map_err(|e| ProviderError::Other(format!("{:?}: {}", model_path.join("config.json"), e)))?;
+        let config: BertConfig = serde_json::from_str(&config_string)
+            .map_err(|e| ProviderError::Other(format!("config.json: {}", e)))?;
+        let sizes = model_sizes(model_path)?;
+
+        if sizes.hidden_size != env.config.dim() {
+            return Err(ProviderError::Other(format!(
+                "the local model gives {} dimensional vectors, set dim to {} in config.yaml",
+                sizes.hidden_size, sizes.hidden_size
+            )));
+        }
+
+        let mut tokenizer = Tokenizer::from_file(model_path.join("tokenizer.json"))
+            .map_err(|e| ProviderError::Other(format!("tokenizer.json: {}", e)))?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: sizes.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_err(|e| ProviderError::Other(format!("tokenizer.json: {}", e)))?;
+
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(
+                &[model_path.join("model.safetensors")], DTYPE, &device
+            ).map_err(candle_error)?
+        };
+        let model = BertModel::load(vb, &config).map_err(candle_error)?;
+
+        Ok(Self {
+            model: Arc::new(model),
+            tokenizer: Arc::new(tokenizer),
+            device,
+        })
+    }
+
+    /// mean pooled and normalized vectors, one row per input
+    fn embed(&self, input: Vec<String>) -> Result<(Vec<Vec<f32>>, u32), ProviderError> {
+        let encodings = self.tokenizer.encode_batch(input, true)
+            .map_err(|e| ProviderError::Other(format!("local embedding error: {}", e)))?;
+
+        let tokens = encodings
+            .iter()
+            .map(|e| e.get_attention_mask().iter().sum::<u32>())
+            .sum::<u32>();
+
+        let ids = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), &self.device))
+            .collect::<candle_core::Result<Vec<Tensor>>>()
+            .map_err(candle_error)?;
+        let masks = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), &self.device))
+            .collect::<candle_core::Result<Vec<Tensor>>>()
+            .map_err(candle_error)?;
+
+        let vectors = (|| {
+            let input_ids = Tensor::stack(&ids, 0)?;
+            let attention_mask = Tensor::stack(&masks, 0)?;
+            let token_type_ids = input_ids.zeros_like()?;
+
+            // (batch, tokens, hidden)
+            let output = self.model.forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+
+            let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+            let sum = output.broadcast_mul(&mask)?.sum(1)?;
+            let count = mask.sum(1)?;
+            let mean = sum.broadcast_div(&count)?;
+
+            let norm = mean.sqr()?.sum_keepdim(1)?.sqrt()?;
+            mean.broadcast_div(&norm)?.to_vec2::<f32>()
+        })().map_err(candle_error)?;
+
+        Ok((vectors, tokens))
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for LocalEmbedding {
+
+    async fn embedding_compute(&self, source: Arc<dyn Array>)
+        -> Result<(Float32Array, u32), ProviderError>
+    {
+        // the model keeps a core busy for a while, not on a runtime thread
+        let local = self.clone();
+        let input = providers::array_strings(&source);
+        let (vectors, tokens) = tokio::task::spawn_blocking(move || local.embed(input))
+            .await
+            .map_err(|e| ProviderError::Other(format!("local embedding error: {}", e)))??;
+
+        let mut builder = Float32Builder::new();
+        for v in vectors.iter() {
+            builder.append_slice(v);
+        }
+        Ok((builder.finish(), tokens))
+    }
+}
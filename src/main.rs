@@ -1,26 +1,34 @@
 use std::io;
-use std::fs::File;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use std::io::{stdout, Write, Read};
-use futures::{Future, StreamExt};
+use std::sync::Arc;
+use std::process;
+use std::io::{stdout, Write};
+use futures::StreamExt;
 //use std::env as std_env;
 
 use clap::{
     Parser,
     Args,
     Subcommand,
-    Command,
-
 };
 
-use md5;
-
-use tokio;
 use tokio::task::JoinSet;
-use tokio::{runtime::Handle, task};
+use tokio::sync::Semaphore;
 
 mod openai_utils;
-use openai_utils::OpenAI;
+mod local_embedding_utils;
+mod ollama_utils;
+mod llamacpp_utils;
+mod cassette_utils;
+mod retry_utils;
+mod json_utils;
+mod cache_utils;
+use cache_utils::Cache;
+mod usage_utils;
+
+mod providers;
+use providers::Providers;
 
 mod file_utils;
 
@@ -29,6 +37,15 @@ mod prompt_utils;
 
 mod pooling;
 
+mod symbol_utils;
+use symbol_utils::Symbol;
+
+mod doc_utils;
+mod notebook_utils;
+
+mod tokenizer_utils;
+use tokenizer_utils::TokenCounter;
+
 mod embeding_utils;
 use embeding_utils::Embedding;
 
@@ -40,181 +57,562 @@ mod env;
 mod config;
 
 
+/// what the code and its classes and functions do. the parser finds the
+/// symbols when there is a grammar for the language, otherwise the model does
+async fn analyse_file(
+    env: &env::Env,
+    client: &Providers,
+    lang: &str,
+    file_content: &str,
+    symbols: Option<&[Symbol]>,
+) -> Result<(structs::GPTResponse, structs::Usage), providers::ProviderError> {
+    let symbols = match symbols {
+        None => return client.chat.analyse_source(
+            file_content.to_string(), lang.to_string(), env.config.language()
+        ).await,
+        Some(s) => s,
+    };
+
+    let (response, tokens) = client.chat.analyse_symbols(
+        file_content.to_string(),
+        lang.to_string(),
+        symbols.iter().map(|s| s.describe()).collect(),
+        env.config.language()
+    ).await?;
+
+    let mut classes = vec![];
+    let mut functions = vec![];
+    for (i, s) in symbols.iter().enumerate() {
+        let purpose = response.symbols
+            .iter()
+            .find(|p| p.id == i)
+            .map(|p| p.purpose.clone())
+            .unwrap_or_default();
+        let c = structs::CodeDescription {
+            name: s.name.clone(),
+            md5: None,
+            source_code: s.source_code.clone(),
+            purpose,
+            lang: None,
+            file: None,
+            code_type: Some(s.code_type.clone()),
+            start_line: Some(s.start_line),
+            end_line: Some(s.end_line),
+            start_byte: Some(s.start_byte),
+            end_byte: Some(s.end_byte),
+        };
+        if s.code_type == "class" {
+            classes.push(c);
+        } else {
+            functions.push(c);
+        }
+    }
+
+    Ok((
+        structs::GPTResponse {
+            purpose: response.purpose,
+            classes,
+            functions,
+        },
+        tokens
+    ))
+}
+
+/// a file too large for one prompt, analysed in parts cut between symbols.
+/// the purposes of the parts make the purpose of the file
+async fn analyse_parts(
+    env: &env::Env,
+    client: &Providers,
+    lang: &str,
+    file_content: &str,
+    symbols: Option<&[Symbol]>,
+    max_tokens: usize,
+) -> Result<(structs::GPTResponse, structs::Usage), providers::ProviderError> {
+    let counter = TokenCounter::new(&env.config.analyse_model());
+    let parts = symbol_utils::split(
+        file_content, symbols, max_tokens, |s| counter.count(s)
+    );
+    if parts.len() < 2 {
+        return analyse_file(env, client, lang, file_content, symbols).await;
+    }
+
+    let mut response = structs::GPTResponse {
+        purpose: "".to_string(),
+        classes: vec![],
+        functions: vec![],
+    };
+    let mut tokens = structs::Usage::default();
+    let mut purposes = vec![];
+    for (start, end) in parts {
+        let part = &file_content[start..end];
+        // lines before the part, the model sees it from its own line 1
+        let skipped_lines = symbol_utils::line_of(file_content, start) - 1;
+        // a symbol belongs to the part it starts in
+        let part_symbols = symbols.map(|s| s.iter()
+            .filter(|s| s.start_byte >= start && s.start_byte < end)
+            .map(|s| Symbol {
+                start_byte: s.start_byte - start,
+                end_byte: s.end_byte - start,
+                start_line: s.start_line - skipped_lines,
+                end_line: s.end_line - skipped_lines,
+                ..s.clone()
+            })
+            .collect::<Vec<Symbol>>()
+        );
+        let (mut r, t) = analyse_file(env, client, lang, part, part_symbols.as_deref()).await?;
+        // back to the bytes and lines of the whole file
+        for c in r.classes.iter_mut().chain(r.functions.iter_mut()) {
+            c.start_byte = c.start_byte.map(|b| b + start);
+            c.end_byte = c.end_byte.map(|b| b + start);
+            c.start_line = c.start_line.map(|l| l + skipped_lines);
+            c.end_line = c.end_line.map(|l| l + skipped_lines);
+        }
+        tokens += t;
+        purposes.push(format!(
+            "lines {}-{}: {}",
+            symbol_utils::line_of(file_content, start),
+            symbol_utils::line_of(file_content, end.saturating_sub(1)),
+            r.purpose
+        ));
+        response.classes.extend(r.classes);
+        response.functions.extend(r.functions);
+    }
+    // one purpose for the whole file, the parts one per line if that fails
+    response.purpose = match client.chat.merge_purposes(
+        purposes.clone(), lang.to_string(), env.config.language()
+    ).await {
+        Ok((purpose, t)) => {
+            tokens += t;
+            purpose
+        },
+        Err(_) => purposes.join("\n"),
+    };
+    Ok((response, tokens))
+}
+
 async fn embedding_file(
     env: env::Env,
-    f_name: String,
-    lang: String,
-    f_path: String,
-    md5_value: String,
-    file_content: String,
+    client: Arc<Providers>,
+    embedding_obj: Arc<Embedding>,
+    file: structs::SourceFile,
     is_update: Option<bool>,
-) {
-    let client = OpenAI::new(&env);
-    let embedding_obj = Embedding::new(
-        &env, &client
-    ).await.unwrap();
-
+) -> bool {
+    if doc_utils::is_doc(&file.lang) {
+        return embedding_doc(embedding_obj, file, is_update).await;
+    }
+    let structs::SourceFile {
+        name: f_name, lang, path: f_path, md5: md5_value, content: file_content
+    } = file;
     //println!("f: {:?}", f_name);
 
-    let (response, a_tockens) = client.analyse_source(
-        file_content.clone(), lang.to_string(), env.config.language().to_string()
-    ).await.unwrap();
+    let symbols = symbol_utils::extract(&lang, &f_path, &file_content);
+    let prompt = match symbols {
+        Some(ref s) => prompt_utils::analyse_symbols_prompt(
+            lang.to_string(),
+            file_content.clone(),
+            s.iter().map(|s| s.describe()).collect(),
+            env.config.language().to_string()
+        ),
+        None => prompt_utils::analyse_source_file_prompt(
+            lang.to_string(), file_content.clone(), env.config.language().to_string()
+        ),
+    };
+    let counter = TokenCounter::new(&env.config.analyse_model());
+    let prompt_tokens = counter.count(&prompt);
+    let cache = Cache::new(&env);
+    let mut failed = false;
+    let (response, a_tockens) = if let Some(response) = cache.analysis(&md5_value, &lang) {
+        (response, structs::Usage::default())
+    } else {
+        let analysed = if prompt_tokens > env.config.analyse_max_tokens() {
+            // room left for code once the instructions are in the prompt
+            let max_tokens = env.config.analyse_max_tokens()
+                .saturating_sub(prompt_tokens.saturating_sub(counter.count(&file_content)))
+                .max(1);
+            println!(
+                "{} is too large to analyse at once ({} tokens), analysing it in parts",
+                f_path, prompt_tokens
+            );
+            analyse_parts(&env, &client, &lang, &file_content, symbols.as_deref(), max_tokens).await
+        } else {
+            analyse_file(&env, &client, &lang, &file_content, symbols.as_deref()).await
+        };
+        match analysed {
+            Ok(r) => {
+                cache.put_analysis(&md5_value, &lang, &r.0);
+                r
+            },
+            Err(e) => {
+                // keep the file searchable, marked failed so the next init tries again
+                println!("{} failed to analyse, {}", f_path, e);
+                failed = true;
+                (
+                    structs::GPTResponse {
+                        purpose: "".to_string(),
+                        classes: vec![],
+                        functions: vec![],
+                    },
+                    structs::Usage::default()
+                )
+            }
+        }
+    };
 
     let file_des = structs::CodeDescription {
-        //line_number: 0,
-        //lines: 0,
         file: Some(f_path.clone()),
         md5: Some(md5_value.clone()),
-        code_type: Some("file".to_string()),
+        code_type: Some(if failed { "failed" } else { "file" }.to_string()),
         lang: Some(lang.to_string()),
         name: f_name,
         purpose: response.clone().purpose,
-        source_code: file_content,
+        start_line: Some(1),
+        end_line: Some(symbol_utils::line_of(&file_content, file_content.trim_end().len())),
+        start_byte: Some(0),
+        end_byte: Some(file_content.len()),
+        source_code: file_content.clone(),
     };
 
+    let mut rows = vec![file_des.clone()];
+    for (code_type, list) in [("class", response.classes), ("function", response.functions)] {
+        for c in list {
+            // the parser knows where a symbol is, the model does not
+            let (start_byte, end_byte, start_line, end_line) = match c.start_line {
+                Some(_) => (c.start_byte, c.end_byte, c.start_line, c.end_line),
+                None => match symbol_utils::locate(&file_content, &c.source_code) {
+                    Some((sb, eb, sl, el)) => (Some(sb), Some(eb), Some(sl), Some(el)),
+                    None => (None, None, None, None),
+                },
+            };
+            rows.push(structs::CodeDescription {
+                file: Some(f_path.clone()),
+                md5: Some(md5_value.clone()),
+                code_type: Some(code_type.to_string()),
+                lang: Some(lang.to_string()),
+                name: c.name,
+                purpose: c.purpose,
+                source_code: c.source_code,
+                start_line,
+                end_line,
+                start_byte,
+                end_byte,
+            });
+        }
+    };
+
+    // a notebook is analysed as the source made of its cells,
+    // where a row is in the notebook is the cells it spans
+    if notebook_utils::is_notebook(&f_path) {
+        for row in rows.iter_mut() {
+            (row.start_line, row.end_line) = match (row.start_byte, row.end_byte) {
+                (Some(sb), Some(eb)) => (
+                    notebook_utils::cell_of(&lang, &file_content, sb),
+                    notebook_utils::cell_of(&lang, &file_content, eb.saturating_sub(1).max(sb)),
+                ),
+                _ => (None, None),
+            };
+            (row.start_byte, row.end_byte) = (None, None);
+        }
+    }
+
     if is_update == Some(true) {
-        embedding_obj.delete_file(file_des.clone()).await.unwrap();
+        embedding_obj.delete_file(file_des).await.unwrap();
     }
 
-    let mut e_tokens = embedding_obj.add_data(file_des).await.unwrap();
-    for c in response.classes {
-        //println!("c: {:?}", c);
-        let data = structs::CodeDescription {
-            file: Some(f_path.clone()),
-            md5: Some(md5_value.clone()),
-            code_type: Some("class".to_string()),
-            lang: Some(lang.to_string()),
-            name: c.name,
-            purpose: c.purpose,
-            source_code: c.source_code,
-        };
-        e_tokens += embedding_obj.add_data(data).await.unwrap();
-    };
-    for c in response.functions {
-        //println!("c: {:?}", c);
-        let data = structs::CodeDescription {
-            file: Some(f_path.clone()),
-            md5: Some(md5_value.clone()),
-            code_type: Some("function".to_string()),
-            lang: Some(lang.to_string()),
-            name: c.name,
-            purpose: c.purpose,
-            source_code: c.source_code,
-        };
-        e_tokens += embedding_obj.add_data(data).await.unwrap();
+    let e_tokens = match embedding_obj.add_data_batch(rows).await {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{} failed to embed, {}", f_path, e);
+            return false
+        },
     };
     println!(
         "{}  analysing use tokens: {:?}    embedding use tokens: {:?}",
-        f_path, a_tockens, e_tokens
+        f_path, a_tockens.total(), e_tokens
     );
+    !failed
 }
 
-async fn force_init(env: env::Env, ) {
-
-    let path = env.work_dir();
+/// a markdown or rst file, one `doc` row per section, embedded without analysing
+async fn embedding_doc(
+    embedding_obj: Arc<Embedding>,
+    file: structs::SourceFile,
+    is_update: Option<bool>,
+) -> bool {
+    let structs::SourceFile {
+        name: f_name, lang, path: f_path, md5: md5_value, content: file_content
+    } = file;
+    let file_name = Path::new(&f_path).file_name().unwrap().to_str().unwrap().to_string();
+    let rows: Vec<structs::CodeDescription> = doc_utils::sections(&lang, &file_name, &file_content)
+        .into_iter()
+        .map(|s| structs::CodeDescription {
+            file: Some(f_path.clone()),
+            md5: Some(md5_value.clone()),
+            code_type: Some("doc".to_string()),
+            lang: Some(lang.clone()),
+            name: s.title,
+            purpose: "".to_string(),
+            source_code: file_content[s.start_byte..s.end_byte].to_string(),
+            start_line: Some(symbol_utils::line_of(&file_content, s.start_byte)),
+            end_line: Some(symbol_utils::line_of(&file_content, s.end_byte.saturating_sub(1))),
+            start_byte: Some(s.start_byte),
+            end_byte: Some(s.end_byte),
+        })
+        .collect();
 
-    let client = OpenAI::new(&env);
-    let embedding_obj = Embedding::new(
-        &env, &client
-    ).await.unwrap();
+    if is_update == Some(true) {
+        embedding_obj.delete_file(structs::CodeDescription {
+            file: Some(f_path.clone()),
+            md5: None,
+            code_type: None,
+            lang: None,
+            name: f_name,
+            purpose: "".to_string(),
+            source_code: "".to_string(),
+            start_line: None,
+            end_line: None,
+            start_byte: None,
+            end_byte: None,
+        }).await.unwrap();
+    }
 
-    embedding_obj.clean_all().await.unwrap();
+    let e_tokens = match embedding_obj.add_data_batch(rows).await {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{} failed to embed, {}", f_path, e);
+            return false
+        },
+    };
+    println!("{}  embedding use tokens: {:?}", f_path, e_tokens);
+    true
+}
 
-    let mut file_list: Vec<(PathBuf, String)> = Vec::new();
-    file_utils::list_path(
-        path, &mut file_list, &env.ignore, &env.language_extensions
-    );
+/// runs embedding_file for the files while the budget lasts, at most
+/// `workers` at a time, true when every file was indexed. an error when
+/// files failed, they are tried again by the next init
+async fn embedding_files(
+    env: &env::Env,
+    client: Arc<Providers>,
+    embedding_obj: Arc<Embedding>,
+    file_list: Vec<ProjectFile>,
+    is_update: Option<bool>,
+) -> Result<bool, String> {
+
+    let cache = Cache::new(env);
+    let estimates: Vec<(u64, f64)> = file_list.iter().map(|(_, _, programming_lang, code, md5_value)| {
+        if cache.has_analysis(md5_value, programming_lang) {
+            (0, 0.0)
+        } else {
+            usage_utils::estimate(&env.config, programming_lang, code)
+        }
+    }).collect();
+    let (tokens, cost) = estimates.iter().fold((0, 0.0), |a, e| (a.0 + e.0, a.1 + e.1));
+    println!("estimated: {} tokens, ${:.4}", tokens, cost);
+    if !env.budget.fits(tokens, cost) {
+        println!("the estimate is over the budget, indexing stops when it is spent");
+    }
 
+    let workers = Arc::new(Semaphore::new(env.config.workers()));
     let mut job_set = JoinSet::new();
-    for (f, programming_lang) in file_list.iter() {
+    let mut left = 0;
+    for ((_, f_path, programming_lang, code, md5_value), (t, c)) in file_list.into_iter().zip(estimates) {
 
-        let f_path = f.canonicalize().unwrap().to_str().unwrap().to_string().clone();
-        let f_name = f.to_str().unwrap().to_string().clone();
-        let programming_lang = programming_lang.clone();
+        let permit = workers.clone().acquire_owned().await.unwrap();
 
+        // files that are not scheduled keep their old rows, or none,
+        // so the next incremental init picks them up
+        if env.budget.exceeded() || !env.budget.reserve(t, c) {
+            left += 1;
+            continue;
+        }
 
         println!("analyse: {:?}", f_path);
-        //let (f, programming_lang) = file_list.get(0).unwrap();
-        let mut code = String::new();
-        let _ = File::open(f).unwrap().read_to_string(&mut code);
-
-        let md5_value = format!("{:x}", md5::compute(code.clone()));
-
         let _env = env.clone();
-        
+        let client = client.clone();
+        let embedding_obj = embedding_obj.clone();
+        let file = structs::SourceFile {
+            name: f_path.clone(),
+            lang: programming_lang,
+            path: f_path,
+            md5: md5_value,
+            content: code,
+        };
+
         job_set.spawn(async move {
-            embedding_file(
-                _env, f_name, 
-                programming_lang, f_path, 
-                md5_value, code, None
-            ).await;
+            let budget = _env.budget.clone();
+            let indexed = embedding_file(_env, client, embedding_obj, file, is_update).await;
+            budget.release(t, c);
+            drop(permit);
+            indexed
         });
-        //embedding_file(
-        //    _env, f_name, 
-        //    programming_lang, f_path, 
-        //    md5_value, code
-        //).await;
     };
-    let mut seen = vec![];
+
+    let mut failed = 0;
     while let Some(res) = job_set.join_next().await {
-        res.unwrap();
-        seen.push(true);
+        if !res.unwrap() {
+            failed += 1;
+        }
     }
+    embedding_obj.checkout_latest().await.unwrap();
 
-    if seen.len() == file_list.len(){
-        println!("Embedding Done");
-        let tokens = embedding_obj.update_summary(env.config.language()).await;
+    if left > 0 {
         println!(
-            "projedct summary embedding use tokens: {:?}",
-            tokens
+            "budget reached ({}), {} files are not indexed yet, run \"readit init --resume\" to continue",
+            env.budget, left
         );
-    };
+    }
+    if failed > 0 {
+        return Err(format!(
+            "{} files failed, run \"readit init --resume\" to try them again", failed
+        ))
+    }
+    Ok(left == 0)
 }
 
-async fn init(env: env::Env ) {
-
-    let client = OpenAI::new(&env);
-    let embedding_obj = Embedding::new(
-        &env, &client
-    ).await.unwrap();
+/// (path, path relative to the project, programming language, content, md5)
+type ProjectFile = (PathBuf, String, String, String, String);
 
-    let path = env.work_dir();
+/// every file of the project that is not ignored,
+/// and the files that can not be read as source, (relative path, why)
+fn project_files(env: &env::Env) -> (Vec<ProjectFile>, Vec<(String, String)>) {
 
     let mut file_list: Vec<(PathBuf, String)> = Vec::new();
     file_utils::list_path(
-        path, &mut file_list, &env.ignore, &env.language_extensions
+        env.work_dir(), &mut file_list, &env.ignore, &env.language_extensions
     );
 
-    let mut _file_list:Vec<(PathBuf, String, String, String, String)> = Vec::new();
+    let mut _file_list:Vec<ProjectFile> = Vec::new();
+    let mut skipped: Vec<(String, String)> = Vec::new();
     for (f, programming_lang) in file_list.iter() {
 
-        let f_path = f.canonicalize().unwrap().to_str().unwrap().to_string().clone();
-        //let f_name = f.to_str().unwrap().to_string().clone();
-        //let programming_lang = programming_lang.clone();
+        let f_path = file_utils::relative_path(env.work_dir(), f);
+
+        // the outputs of a notebook, images and all, make it look large and
+        // minified, the limits are for the source made of its cells
+        let (max_file_size, max_line_length) = match notebook_utils::is_notebook(&f_path) {
+            true => (u64::MAX, usize::MAX),
+            false => (env.config.max_file_size(), env.config.max_line_length()),
+        };
+        let code = match file_utils::read_source(f, max_file_size, max_line_length) {
+            Ok(code) => code,
+            Err(reason) => {
+                skipped.push((f_path, reason));
+                continue;
+            }
+        };
 
-        //let (f, programming_lang) = file_list.get(0).unwrap();
-        let mut code = String::new();
-        let _ = File::open(f).unwrap().read_to_string(&mut code);
+        // notebooks go on as source in their kernel's language, outputs dropped
+        let (programming_lang, code) = match notebook_utils::is_notebook(&f_path) {
+            false => (programming_lang.clone(), code),
+            true => match notebook_utils::to_source(&code).and_then(|(lang, code)| {
+                file_utils::check_source(
+                    &code, env.config.max_file_size(), env.config.max_line_length()
+                ).map(|_| (lang, code))
+            }) {
+                Ok(r) => r,
+                Err(reason) => {
+                    skipped.push((f_path, reason));
+                    continue;
+                }
+            },
+        };
 
         let md5_value = format!("{:x}", md5::compute(code.clone()));
 
-        if embedding_obj.is_file_change(&f_path, &md5_value).await.unwrap() {
-            _file_list.push((
-                f.clone(),
-                f_path.clone(),
-                programming_lang.clone(),
-                code,
-                md5_value
-            ));
+        _file_list.push((
+            f.clone(),
+            f_path,
+            programming_lang,
+            code,
+            md5_value
+        ));
+    };
+    (_file_list, skipped)
+}
+
+fn print_skipped(skipped: &[(String, String)]) {
+    if skipped.is_empty() {
+        return
+    }
+    println!("skipped {} files:", skipped.len());
+    for (f_path, reason) in skipped.iter() {
+        println!("    {}: {}", f_path, reason)
+    };
+}
+
+async fn force_init(env: env::Env, ) -> Result<(), String> {
+
+    let client = Arc::new(Providers::new(&env).map_err(|e| e.to_string())?);
+    let embedding_obj = Arc::new(Embedding::new(
+        &env, client.clone(), true
+    ).await.map_err(|e| e.to_string())?);
+
+    embedding_obj.clean_all().await.unwrap();
+
+    let (file_list, skipped) = project_files(&env);
+
+    let indexed = embedding_files(&env, client, embedding_obj.clone(), file_list, None).await;
+    if indexed == Ok(true) {
+        println!("Embedding Done");
+        let tokens = embedding_obj.update_summary(env.config.language()).await;
+        println!(
+            "projedct summary embedding use tokens: {:?}",
+            tokens
+        );
+    };
+    print_skipped(&skipped);
+    indexed.map(|_| ())
+}
+
+/// `ask` updates the index with it too, without listing the skipped files every time
+async fn init(env: env::Env, show_skipped: bool) -> Result<(), String> {
+
+    let client = Arc::new(Providers::new(&env).map_err(|e| e.to_string())?);
+    let embedding_obj = Arc::new(Embedding::new(
+        &env, client.clone(), false
+    ).await.map_err(|e| e.to_string())?);
+
+    let (file_list, skipped) = project_files(&env);
+
+    // rows of files that were deleted or are ignored now. absolute paths are
+    // of a moved project that rebase-paths has not fixed yet, and skipped
+    // files are still there, only unreadable this time, their rows stay
+    let current: HashSet<&String> = file_list.iter()
+        .map(|file| &file.1)
+        .chain(skipped.iter().map(|(f_path, _)| f_path))
+        .collect();
+    let orphans: Vec<String> = embedding_obj.files().await.unwrap()
+        .into_iter()
+        .filter(|f| !Path::new(f).is_absolute() && !current.contains(f))
+        .collect();
+    if !orphans.is_empty() {
+        let rows = embedding_obj.delete_files(&orphans).await.unwrap();
+        println!("removed {} rows of {} files that are deleted or ignored", rows, orphans.len());
+    }
+
+    let mut _file_list:Vec<ProjectFile> = Vec::new();
+    for file in file_list {
+        if embedding_obj.is_file_change(&file.1, &file.4).await.unwrap() {
+            _file_list.push(file);
         }
     };
 
     if _file_list.is_empty() {
-        return
+        // the project summary still tells about the removed files
+        if !orphans.is_empty() {
+            let tokens = embedding_obj.update_summary(env.config.language()).await;
+            println!(
+                "projedct summary embedding use tokens: {:?}",
+                tokens
+            );
+        }
+        if show_skipped {
+            print_skipped(&skipped);
+        }
+        return Ok(())
     }
 
     println!("these files is changed, would you want to re-embedding them?");
-    for i in _file_list.clone() {
+    for i in _file_list.iter() {
         println!("    {}", i.1)
     };
 
@@ -222,44 +620,28 @@ async fn init(env: env::Env ) {
 
     let mut y_n = String::new();
     let _ = io::stdin().read_line(&mut y_n);
-    println!("");
+    println!();
     y_n = y_n.replace("\n", "").replace(" ", "").replace("\r", "");
-    if y_n == "No".to_string() || y_n == "no" {
+    if y_n == "No" || y_n == "no" {
         println!("....");
-        return
+        if !orphans.is_empty() {
+            let tokens = embedding_obj.update_summary(env.config.language()).await;
+            println!(
+                "projedct summary embedding use tokens: {:?}",
+                tokens
+            );
+        }
+        if show_skipped {
+            print_skipped(&skipped);
+        }
+        return Ok(())
     }
     println!("analysing....");
-    let mut job_set = JoinSet::new();
-    for (f, f_path, programming_lang, code, md5_value) in _file_list.clone() {
-
-        println!("analyse: {:?}", f_path);
-        let _env = env.clone();
-        let f_name = f.to_str().unwrap().to_string().clone();
-        let programming_lang = programming_lang.clone();
-        
-        job_set.spawn(async move {
-            embedding_file(
-                _env, f_name, 
-                programming_lang, f_path, 
-                md5_value, code, Some(true)
-            ).await;
-        });
 
-    };
-
-    let mut seen = vec![];
-    while let Some(res) = job_set.join_next().await {
-        res.unwrap();
-        seen.push(true);
-    }
-
-    if seen.len() == _file_list.len(){
+    let indexed = embedding_files(&env, client, embedding_obj.clone(), _file_list, Some(true)).await;
+    if indexed == Ok(true) {
         println!("Embedding Done");
 
-        let client = OpenAI::new(&env);
-        let embedding_obj = Embedding::new(
-            &env, &client
-        ).await.unwrap();
         let tokens = embedding_obj.update_summary(env.config.language()).await;
 
         println!(
@@ -268,11 +650,41 @@ async fn init(env: env::Env ) {
         );
     }
 
-    println!("Embedding Done");
-    
+    if show_skipped {
+        print_skipped(&skipped);
+    }
+    indexed.map(|_| ())
 }
 
 
+/// `readit rebase-paths`, for an index built before paths were stored
+/// relative to the project, or copied from a checkout somewhere else
+async fn rebase_paths(env: &env::Env, from: Option<String>) -> Result<(), String> {
+    if env.is_new_project() {
+        println!("there is no index in this project yet");
+        return Ok(())
+    }
+    let client = Arc::new(Providers::new(env).map_err(|e| e.to_string())?);
+    let embedding_obj = Embedding::new(env, client, false).await.map_err(|e| e.to_string())?;
+
+    let root = match from {
+        Some(f) => PathBuf::from(f),
+        None => match file_utils::guess_root(env.work_dir(), &embedding_obj.files().await.unwrap()) {
+            Some(r) => r,
+            None => {
+                println!("every path in the index is relative to the project already, or none of them exists here");
+                return Ok(())
+            }
+        },
+    };
+    let (rebased, left, tokens) = embedding_obj.rebase_paths(&root).await.map_err(|e| e.to_string())?;
+    println!("rebased {} files from {}, embedding use tokens: {}", rebased, root.display(), tokens);
+    if left > 0 {
+        println!("{} files are not under {} and keep their absolute paths", left, root.display());
+    }
+    Ok(())
+}
+
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -285,28 +697,63 @@ struct Cli {
     #[arg(short, long)]
     path: Option<String>,
 
+    /// set a config field, over the config files and READIT_* variables, `-c chat_model=gpt-4o-mini`
+    #[arg(short = 'c', long = "config", value_name = "KEY=VALUE", global = true,
+        value_parser = |arg: &str| config::Config::parse_arg(arg).map(|_| arg.to_string()))]
+    config: Vec<String>,
+
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// init
-    Init,
+    Init(InitArgs),
 
     /// ask something
     Ask(AskArgs),
+
+    /// tokens used and what they cost, per model, command and day
+    Usage,
+
+    /// make the absolute paths of an index built elsewhere relative to the project
+    RebasePaths(RebaseArgs),
+}
+
+#[derive(Args, Debug)]
+struct BudgetArgs {
+    /// stop when this many tokens are spent, over max_tokens of the config
+    #[arg(long)]
+    max_tokens: Option<u64>,
+
+    /// stop when this many USD are spent, over max_cost of the config
+    #[arg(long)]
+    max_cost: Option<f64>,
 }
 
 #[derive(Args, Debug)]
 struct InitArgs {
-    /// force init
+    /// only index the files that changed or are not indexed yet
     #[arg(short, long)]
-    force: bool,
+    resume: bool,
+
+    #[command(flatten)]
+    budget: BudgetArgs,
+}
+
+#[derive(Args, Debug)]
+struct RebaseArgs {
+    /// where the project was when it was indexed, guessed from the paths when not given
+    #[arg(long)]
+    from: Option<String>,
 }
 
 #[derive(Args, Debug)]
 struct AskArgs {
     /// the question
     query: String,
+
+    #[command(flatten)]
+    budget: BudgetArgs,
 }
 
 
@@ -317,47 +764,117 @@ async fn main() {
     let command = Cli::parse();
     //println!("{:?}", command);
 
-    let _env = env::Env::new(command.path);
+    let mut _env = env::Env::new(command.path, command.config);
 
-    if !_env.check_openai_key() {
+    _env.command = match command.command {
+        Commands::Init(_) => "init",
+        Commands::Ask(_) => "ask",
+        Commands::Usage => "usage",
+        Commands::RebasePaths(_) => "rebase-paths",
+    }.to_string();
+
+    match command.command {
+        Commands::Init(InitArgs { ref budget, .. }) | Commands::Ask(AskArgs { ref budget, .. }) => {
+            _env.set_budget(budget.max_tokens, budget.max_cost);
+        },
+        Commands::Usage | Commands::RebasePaths(_) => {},
+    }
+
+    if let Commands::Usage = command.command {
+        usage_utils::report(&_env);
+        return
+    }
+
+    if let Commands::RebasePaths(ref args) = command.command {
+        if let Err(e) = rebase_paths(&_env, args.from.clone()).await {
+            println!("{}", e);
+            process::exit(1);
+        }
+        return
+    }
+
+    if _env.use_openai() && !_env.check_openai_key() {
         println!("Please set openai key first, \nrun \"export OPENAI_KEY=your_openai_key\" in your shell, \nor set openai_key in $HOME/.readit/config.yaml \nyou can run \"readit -h \" for help.");
         return
     }
 
+    if _env.use_azure() && !_env.check_azure() {
+        println!("Please set azure_base and azure_key in $HOME/.readit/config.yaml, \nor run \"export AZURE_OPENAI_KEY=your_azure_key\" in your shell, \nyou can run \"readit -h \" for help.");
+        return
+    }
 
     match command.command {
-        Commands::Init => {
-            force_init(_env).await;
+        Commands::Init(args) => {
+            let result = if args.resume && !_env.is_new_project() {
+                init(_env, true).await
+            } else {
+                force_init(_env).await
+            };
+            if let Err(e) = result {
+                println!("{}", e);
+                process::exit(1);
+            }
         },
         Commands::Ask(args) => {
             
-            init(_env.clone()).await;
+            if let Err(e) = init(_env.clone(), false).await {
+                println!("{}", e);
+                process::exit(1);
+            }
 
-            let client = OpenAI::new(&_env);
+            let client = match Providers::new(&_env) {
+                Ok(c) => Arc::new(c),
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                },
+            };
 
             if _env.is_new_project() {
                 println!("Please run init command first, you can run \"readit -h \" for help.");
                 return
             }
 
-            let embedding_obj = Embedding::new(
-                &_env, &client
-            ).await.unwrap();
+            let embedding_obj = match Embedding::new(&_env, client.clone(), false).await {
+                Ok(e) => e,
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                },
+            };
 
             let query = args.query.clone();
-            let (code_list, e_tokens) = embedding_obj.search(query.clone()).await.unwrap();
-            let mut res = client.ask(query, code_list, _env.config.language()).await.unwrap();
+            let (code_list, _e_tokens) = match embedding_obj.search(query.clone()).await {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("{}", e);
+                    return
+                },
+            };
+
+            let prompt_tokens = TokenCounter::new(&_env.config.chat_model()).count(
+                &prompt_utils::ask_prompt(query.clone(), code_list.clone(), _env.config.language())
+            ) as u64;
+            let prompt_cost = usage_utils::cost(
+                &_env.config, &usage_utils::chat_model_name(&_env.config, &_env.config.chat_model()), prompt_tokens, 0
+            ).unwrap_or(0.0);
+            if _env.budget.exceeded() || !_env.budget.reserve(prompt_tokens, prompt_cost) {
+                println!("the question does not fit in the budget ({})", _env.budget);
+                return
+            }
+            let mut res = match client.chat.ask(query, code_list, _env.config.language()).await {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("{}", e);
+                    return
+                },
+            };
             
             let mut lock = stdout().lock();
             while let Some(result) = res.next().await {
                 match result {
-                    Ok(response) => {
-                        response.choices.iter().for_each(|chat_choice| {
-                            //println!("{:?}", chat_choice);
-                            if let Some(ref content) = chat_choice.delta.content {
-                                write!(lock, "{}", content).unwrap();
-                            }
-                        });
+                    Ok(content) => {
+                        write!(lock, "{}", content).unwrap();
                     }
                     Err(err) => {
                         writeln!(lock, "error: {err}").unwrap();
@@ -367,7 +884,8 @@ async fn main() {
             }
             //println!("{}", res);
             //println!("tokens usage: {:?}", a_tokens+e_tokens);
-        }
+        },
+        Commands::Usage | Commands::RebasePaths(_) => {},
     };
 
 }
@@ -0,0 +1,173 @@
+use serde_json::{self, Value};
+
+pub fn is_notebook(file: &str) -> bool {
+    file.ends_with(".ipynb")
+}
+
+/// the name language_extensions uses for a kernel language
+fn kernel_language(name: &str) -> String {
+    match name.to_lowercase().as_str() {
+        "python" | "python3" | "ipython" => "Python",
+        "r" => "R",
+        "julia" => "Julia",
+        "scala" => "Scala",
+        "java" => "Java",
+        "javascript" | "node" => "JavaScript",
+        "typescript" => "TypeScript",
+        "ruby" => "Ruby",
+        "go" => "Go",
+        "rust" => "Rust",
+        "c" => "C",
+        "c++" | "cpp" | "c++17" => "C++",
+        "c#" | "csharp" => "C#",
+        "kotlin" => "Kotlin",
+        "bash" | "sh" | "shell" | "zsh" => "Shell",
+        "matlab" | "octave" => "MATLAB",
+        "lua" => "Lua",
+        "haskell" => "Haskell",
+        "sql" => "SQL",
+        _ => return name.to_string(),
+    }.to_string()
+}
+
+/// how a line comment starts in a language
+fn comment(programming_lang: &str) -> &'static str {
+    match programming_lang {
+        "Scala" | "Java" | "JavaScript" | "TypeScript" | "Go" | "Rust" | "C" | "C++" | "C#"
+        | "Kotlin" | "Swift" | "Groovy" => "//",
+        "Lua" | "Haskell" | "SQL" => "--",
+        "MATLAB" => "%",
+        _ => "#",
+    }
+}
+
+/// the text of a notebook field, a string or a list of lines
+fn text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Array(lines) => lines.iter().filter_map(|l| l.as_str()).collect(),
+        _ => "".to_string(),
+    }
+}
+
+/// a notebook as one source file in its kernel's language, outputs dropped.
+/// every cell starts with a `# %% cell 3` line, markdown cells are comments,
+/// so the file goes through the same analysis as any other source.
+/// gives (the kernel's language, the source)
+pub fn to_source(notebook: &str) -> Result<(String, String), String> {
+    let nb: Value = serde_json::from_str(notebook).map_err(|e| format!("not a notebook, {}", e))?;
+    let cells = nb.get("cells")
+        .and_then(|c| c.as_array())
+        .ok_or("not a notebook of nbformat 4, no cells".to_string())?;
+
+    let metadata = &nb["metadata"];
+    let lang = kernel_language(
+        metadata["kernelspec"]["language"].as_str()
+            .or(metadata["language_info"]["name"].as_str())
+            .unwrap_or("python")
+    );
+    let prefix = comment(&lang);
+
+    let mut source = String::new();
+    for (i, cell) in cells.iter().enumerate() {
+        let cell_type = cell["cell_type"].as_str().unwrap_or("code");
+        let body = text(&cell["source"]);
+        match cell_type {
+            "code" => {
+                source.push_str(&format!("{} %% cell {}\n", prefix, i + 1));
+                source.push_str(&body);
+            },
+            _ => {
+                source.push_str(&format!("{} %% cell {} [{}]\n", prefix, i + 1, cell_type));
+                for line in body.lines() {
+                    match line.is_empty() {
+                        true => source.push_str(&format!("{}\n", prefix)),
+                        false => source.push_str(&format!("{} {}\n", prefix, line)),
+                    }
+                }
+            },
+        }
+        if !source.ends_with('\n') {
+            source.push('\n');
+        }
+        source.push('\n');
+    }
+    Ok((lang, source))
+}
+
+/// the cell a byte of a source made by `to_source` is in, counted from 1.
+/// only the marker lines `to_source` wrote count, not the same words in a cell
+pub fn cell_of(programming_lang: &str, source: &str, byte: usize) -> Option<usize> {
+    let marker = format!("{} %% cell ", comment(programming_lang));
+    let mut cell = None;
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        if offset > byte {
+            break;
+        }
+        if let Some(rest) = line.strip_prefix(&marker) {
+            if let Ok(n) = rest.trim().split(' ').next().unwrap_or("").parse::<usize>() {
+                cell = Some(n);
+            }
+        }
+        offset += line.len();
+    }
+    cell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTEBOOK: &str = r##"{
+        "metadata": {"kernelspec": {"language": "python", "name": "python3"}},
+        "nbformat": 4,
+        "cells": [
+            {"cell_type": "markdown", "source": ["# Title\n", "\n", "some text"]},
+            {"cell_type": "code", "source": "import os\nprint(os.name)", "outputs": [{"output_type": "stream", "text": "posix"}]},
+            {"cell_type": "code", "source": ["def f():\n", "    return 1\n"], "outputs": []}
+        ]
+    }"##;
+
+    #[test]
+    fn to_source_cells() {
+        let (lang, source) = to_source(NOTEBOOK).unwrap();
+        assert_eq!(lang, "Python");
+        assert_eq!(source, "# %% cell 1 [markdown]\n# # Title\n#\n# some text\n\n\
+            # %% cell 2\nimport os\nprint(os.name)\n\n\
+            # %% cell 3\ndef f():\n    return 1\n\n");
+        assert!(!source.contains("posix"));
+    }
+
+    #[test]
+    fn to_source_kernel_language() {
+        let nb = r#"{"metadata": {"language_info": {"name": "rust"}}, "cells": [{"cell_type": "markdown", "source": "note"}]}"#;
+        assert_eq!(to_source(nb).unwrap(), ("Rust".to_string(), "// %% cell 1 [markdown]\n// note\n\n".to_string()));
+    }
+
+    #[test]
+    fn to_source_not_a_notebook() {
+        assert!(to_source("print(1)").is_err());
+        assert!(to_source(r#"{"worksheets": []}"#).is_err());
+    }
+
+    #[test]
+    fn cell_of_bytes() {
+        let (lang, source) = to_source(NOTEBOOK).unwrap();
+        assert_eq!(cell_of(&lang, &source, 0), Some(1));
+        assert_eq!(cell_of(&lang, &source, source.find("print").unwrap()), Some(2));
+        assert_eq!(cell_of(&lang, &source, source.find("return").unwrap()), Some(3));
+        assert_eq!(cell_of(&lang, &source, source.len()), Some(3));
+        assert_eq!(cell_of(&lang, "no cells\n", 3), None);
+    }
+
+    #[test]
+    fn cell_of_only_markers() {
+        let nb = r#"{"cells": [
+            {"cell_type": "code", "source": "x = 1\n"},
+            {"cell_type": "code", "source": "s = \" %% cell 9\"\nif s:\n    # %% cell 7\n    y = 2\n"}
+        ]}"#;
+        let (lang, source) = to_source(nb).unwrap();
+        assert_eq!(cell_of(&lang, &source, source.find("y = 2").unwrap()), Some(2));
+    }
+}
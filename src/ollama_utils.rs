@@ -0,0 +1,263 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use serde_json::{self, json, Value};
+
+use crate::{json_utils, prompt_string, prompt_utils, structs};
+
+use crate::env;
+
+use crate::providers::{
+    self,
+    AskStream,
+    ChatProvider,
+    ProviderError,
+};
+
+/// talks to the native api of an ollama server, `/api/chat`
+pub struct Ollama {
+    client: reqwest::Client,
+    base: String,
+    chat_model: String,
+    analyse_model: String,
+    json_schema: bool,
+}
+
+impl Ollama {
+    pub fn new(env: &env::Env) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base: env.config.ollama_base(),
+            chat_model: env.config.chat_model(),
+            analyse_model: env.config.analyse_model(),
+            json_schema: env.config.json_schema(),
+        }
+    }
+
+    /// `format` is "json", or the json schema the answer must follow
+    fn request(&self, model: &String, system: &str, prompt: String, stream: bool, format: Option<Value>) -> Value {
+        let mut body = json!({
+            "model": model,
+            "stream": stream,
+            "messages": [
+                {"role": "system", "content": system},
+                {"role": "user", "content": prompt},
+            ],
+        });
+        if let Some(format) = format {
+            body["format"] = format;
+        }
+        body
+    }
+
+    /// the whole answer and the tokens it used
+    async fn complete(&self, model: &String, system: &str, prompt: String, format: Option<Value>)
+        -> Result<(String, structs::Usage), ProviderError>
+    {
+        let body = self.request(model, system, prompt, false, format);
+        let response = providers::post_json(
+            &self.client, format!("{}/api/chat", self.base), &body
+        ).await?;
+        let response: Value = response.json()
+            .await
+            .map_err(|e| ProviderError::Other(format!("ollama: {}", e)))?;
+
+        let text = response["message"]["content"].as_str().unwrap_or("").to_string();
+        let tokens = structs::Usage {
+            prompt_tokens: response["prompt_eval_count"].as_u64().unwrap_or(0) as u32,
+            completion_tokens: response["eval_count"].as_u64().unwrap_or(0) as u32,
+        };
+        Ok((text, tokens))
+    }
+
+    /// ollama streams one json object per line
+    async fn complete_stream(&self, model: &String, system: &str, prompt: String)
+        -> Result<AskStream, ProviderError>
+    {
+        let body = self.request(model, system, prompt, true, None);
+        let response = providers::post_json(
+            &self.client, format!("{}/api/chat", self.base), &body
+        ).await?;
+
+        Ok(Box::pin(providers::response_lines(response).map(|line| {
+            let line = line?;
+            if line.is_empty() {
+                return Ok("".to_string());
+            }
+            let frame: Value = serde_json::from_str(&line)
+                .map_err(|e| ProviderError::Other(format!("ollama: {}", e)))?;
+            if let Some(error) = frame["error"].as_str() {
+                return Err(ProviderError::Other(format!("ollama: {}", error)));
+            }
+            Ok(frame["message"]["content"].as_str().unwrap_or("").to_string())
+        })))
+    }
+}
+
+#[async_trait]
+impl ChatProvider for Ollama {
+
+    async fn analyse_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<(structs::GPTResponse, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::analyse_source_file_prompt(
+            programming_lang, code_string, language
+        );
+        let format = if self.json_schema {
+            json_utils::analyse_schema()
+        } else {
+            json!("json")
+        };
+        json_utils::complete_json(prompt, json_utils::JSON_RETRIES, |prompt| self.complete(
+            &self.analyse_model, prompt_string::ANALYSE_SOURCE_FILE_SYSTEM, prompt, Some(format.clone())
+        )).await
+    }
+
+    async fn analyse_symbols(&self,
+        code_string: String, programming_lang: String, symbols: Vec<String>, language: String
+    ) -> Result<(structs::GPTSymbolsResponse, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::analyse_symbols_prompt(
+            programming_lang, code_string, symbols, language
+        );
+        let format = if self.json_schema {
+            json_utils::symbols_schema()
+        } else {
+            json!("json")
+        };
+        json_utils::complete_json(prompt, json_utils::JSON_RETRIES, |prompt| self.complete(
+            &self.analyse_model, prompt_string::ANALYSE_SOURCE_FILE_SYSTEM, prompt, Some(format.clone())
+        )).await
+    }
+
+    async fn split_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<structs::GPTCodeSplitResponse, ProviderError>
+    {
+        let prompt = prompt_utils::split_source_file_prompt(
+            programming_lang, code_string, language
+        );
+        let (text, _) = self.complete(
+            &self.analyse_model, prompt_string::SPLIT_SOURCE_FILE_SYSTEM, prompt, Some(json!("json"))
+        ).await?;
+
+        json_utils::parse(&text).map_err(|e| ProviderError::InvalidJson(e.to_string(), structs::Usage::default()))
+    }
+
+    async fn ask(&self,
+        query: String, code_list: Vec<String>, language: String
+    ) -> Result<AskStream, ProviderError>
+    {
+        let prompt = prompt_utils::ask_prompt(
+            query, code_list, language
+        );
+        self.complete_stream(&self.chat_model, prompt_string::ASSISTANT_SYSTEM, prompt).await
+    }
+
+    async fn chat(&self, message: String) -> Result<AskStream, ProviderError> {
+        self.complete_stream(&self.chat_model, prompt_string::ASSISTANT_SYSTEM, message).await
+    }
+
+    async fn summarize(&self, query: String, language: String)
+        -> Result<(String, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::summarize_prompt(
+            query, language
+        );
+        let (text, tokens) = self.complete(
+            &self.chat_model, prompt_string::ASSISTANT_SYSTEM, prompt, None
+        ).await?;
+        let text = html_escape::decode_html_entities(&text).to_string();
+        Ok((text, tokens))
+    }
+
+    async fn merge_purposes(&self,
+        parts: Vec<String>, programming_lang: String, language: String
+    ) -> Result<(String, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::merge_purposes_prompt(
+            programming_lang, parts, language
+        );
+        let (text, tokens) = self.complete(
+            &self.analyse_model, prompt_string::ASSISTANT_SYSTEM, prompt, None
+        ).await?;
+        let text = html_escape::decode_html_entities(&text).to_string();
+        Ok((text, tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ollama(base: String) -> Ollama {
+        Ollama {
+            client: reqwest::Client::new(),
+            base,
+            chat_model: "chat".to_string(),
+            analyse_model: "analyse".to_string(),
+            json_schema: true,
+        }
+    }
+
+    async fn collect(stream: AskStream) -> Vec<Result<String, ProviderError>> {
+        stream.collect().await
+    }
+
+    #[tokio::test]
+    async fn stream_line_frames() {
+        let (base, server) = providers::stand_in_server(200, "application/x-ndjson", vec![
+            "{\"message\":{\"content\":\"Hel\"},\"done\":false}\n{\"message\":{\"con",
+            "tent\":\"lo\"},\"done\":false}\n\n",
+            "{\"message\":{\"content\":\"\"},\"done\":true,\"eval_count\":2}",
+        ]);
+        let text: String = collect(ollama(base).complete_stream(&"chat".to_string(), "system", "hi".to_string()).await.unwrap())
+            .await
+            .into_iter()
+            .map(|piece| piece.unwrap())
+            .collect();
+        assert_eq!(text, "Hello");
+
+        let request = server.join().unwrap();
+        assert_eq!(request["model"], "chat");
+        assert_eq!(request["stream"], true);
+        assert_eq!(request["messages"][1]["content"], "hi");
+    }
+
+    #[tokio::test]
+    async fn stream_error_frame() {
+        let (base, server) = providers::stand_in_server(200, "application/x-ndjson", vec![
+            "{\"message\":{\"content\":\"a\"}}\n{\"error\":\"model unloaded\"}\n",
+        ]);
+        let pieces = collect(ollama(base).complete_stream(&"chat".to_string(), "system", "hi".to_string()).await.unwrap()).await;
+        assert_eq!(pieces[0].as_ref().unwrap(), "a");
+        assert!(matches!(&pieces[1], Err(ProviderError::Other(e)) if e.contains("model unloaded")));
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn complete_counts_tokens() {
+        let (base, server) = providers::stand_in_server(200, "application/json", vec![
+            "{\"message\":{\"content\":\"{\\\"purpose\\\":\\\"x\\\"}\"},",
+            "\"prompt_eval_count\":12,\"eval_count\":5}",
+        ]);
+        let (text, tokens) = ollama(base)
+            .complete(&"analyse".to_string(), "system", "code".to_string(), Some(json!("json")))
+            .await
+            .unwrap();
+        assert_eq!(text, "{\"purpose\":\"x\"}");
+        assert_eq!((tokens.prompt_tokens, tokens.completion_tokens), (12, 5));
+        assert_eq!(server.join().unwrap()["format"], "json");
+    }
+
+    #[tokio::test]
+    async fn http_error() {
+        let (base, server) = providers::stand_in_server(404, "application/json", vec![
+            "{\"error\":\"model not found\"}",
+        ]);
+        let result = ollama(base).complete(&"chat".to_string(), "system", "hi".to_string(), None).await;
+        assert!(matches!(result, Err(ProviderError::Http { status: 404, ref message, .. }) if message.contains("model not found")));
+        server.join().unwrap();
+    }
+}
@@ -1,89 +1,231 @@
-use tokio::{runtime::Handle, task};
-use std::{iter::once, sync::Arc};
+use std::sync::Arc;
 
-use arrow::array::{Array, Float32Builder, ArrayData};
-use arrow_schema::{DataType, Field, Schema};
+use arrow::array::{Array, Float32Builder};
+use arrow_schema::DataType;
 use arrow_array::{
     cast::AsArray,
     Float32Array,
 };
 
 use async_openai::{
-    Client,
-    config::OpenAIConfig,
+    config::{AzureConfig, Config, OpenAIConfig},
     types::{
+        CreateChatCompletionRequest,
         CreateChatCompletionRequestArgs,
-        ChatCompletionResponseStream,
         CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse,
 
-        ChatCompletionResponseFormat,
-        ChatCompletionResponseFormatType,
+        ResponseFormat,
+        ResponseFormatJsonSchema,
 
         ChatCompletionRequestSystemMessageArgs,
         ChatCompletionRequestUserMessageArgs,
 
         CreateEmbeddingRequest,
+        CreateEmbeddingResponse,
         Embedding,
         EmbeddingInput,
         EncodingFormat
     },
-    error::OpenAIError,
-    
 };
 
-use serde_json;
+use async_trait::async_trait;
+use futures::StreamExt;
 
-use html_escape;
-
-use crate::{prompt_utils, structs};
+use crate::{json_utils, prompt_string, prompt_utils, structs};
 
 use crate::env;
 
-pub struct OpenAI {
-    client: Client<OpenAIConfig>,
+use crate::providers::{
+    self,
+    AskStream,
+    ChatProvider,
+    EmbeddingProvider,
+    ProviderError,
+};
+
+/// one openai or azure endpoint. requests go out with reqwest, the errors of
+/// async-openai's client drop the response headers, and with them the
+/// `Retry-After` of a rate limit
+struct Api<C: Config> {
+    http: reqwest::Client,
+    config: C,
+}
+
+impl<C: Config> Api<C> {
+    fn new(config: C) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    async fn post<B: serde::Serialize>(&self, path: &str, body: &B)
+        -> Result<reqwest::Response, ProviderError>
+    {
+        let url = self.config.url(path);
+        let response = self.http.post(&url)
+            .query(&self.config.query())
+            .headers(self.config.headers())
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| ProviderError::Transport(format!("{}: {}", url, e)))?;
+        providers::check_status(&url, response).await.map_err(|e| match e {
+            ProviderError::Http { message, .. } if message.contains("maximum context length") =>
+                ProviderError::ContextLength(message),
+            e => e,
+        })
+    }
+
+    async fn create(&self, request: CreateChatCompletionRequest)
+        -> Result<CreateChatCompletionResponse, ProviderError>
+    {
+        self.post("/chat/completions", &request)
+            .await?
+            .json()
+            .await
+            .map_err(|e| ProviderError::Transport(format!("chat completion: {}", e)))
+    }
+
+    /// openai streams server-sent events, `data: {...}` up to `data: [DONE]`
+    async fn create_stream(&self, request: CreateChatCompletionRequest)
+        -> Result<AskStream, ProviderError>
+    {
+        let response = self.post("/chat/completions", &request).await?;
+        let frames = providers::response_lines(response)
+            .take_while(|line| futures::future::ready(
+                !matches!(line, Ok(l) if l.trim_start_matches("data:").trim() == "[DONE]")
+            ))
+            .map(|line| {
+                let line = line?;
+                let data = match line.strip_prefix("data:") {
+                    None => return Ok("".to_string()),
+                    Some(d) => d.trim(),
+                };
+                let frame: CreateChatCompletionStreamResponse = serde_json::from_str(data)
+                    .map_err(|e| ProviderError::Transport(format!("chat stream: {}: {}", e, data)))?;
+                Ok(frame.choices
+                    .iter()
+                    .filter_map(|chat_choice| chat_choice.delta.content.clone())
+                    .collect::<String>())
+            });
+        Ok(Box::pin(frames))
+    }
+
+    async fn embeddings(&self, request: CreateEmbeddingRequest)
+        -> Result<CreateEmbeddingResponse, ProviderError>
+    {
+        self.post("/embeddings", &request)
+            .await?
+            .json()
+            .await
+            .map_err(|e| ProviderError::Transport(format!("embeddings: {}", e)))
+    }
+}
+
+/// openai, or any server with the same api.
+/// azure routes every model to its own deployment, so each model gets a client
+pub struct OpenAI<C: Config = OpenAIConfig> {
+    chat_client: Api<C>,
+    analyse_client: Api<C>,
+    embedding_client: Api<C>,
     dim: u32,
     chat_model: String,
     analyse_model: String,
     embedding_model: String,
+    json_schema: bool,
 }
 
-impl OpenAI {
+impl OpenAI<OpenAIConfig> {
     pub fn new(env: &env::Env) -> Self {
 
         let config = OpenAIConfig::new()
-            .with_api_key(env.config.openai_key())
-            .with_api_base(env.config.openai_base())
+            .with_api_key(env.openai_key())
+            .with_api_base(env.openai_base())
         ;
         
         Self {
-            client: Client::with_config(config),
+            chat_client: Api::new(config.clone()),
+            analyse_client: Api::new(config.clone()),
+            embedding_client: Api::new(config),
             dim: env.config.dim() as u32,
             chat_model: env.config.chat_model(),
             analyse_model: env.config.analyse_model(),
             embedding_model: env.config.embedding_model(),
+            json_schema: env.config.json_schema(),
         }
     }
-    
+}
 
-    pub async fn analyse_source(&self,
-        code_string: String, programming_lang: String, language: String
-    ) -> Result<(structs::GPTResponse, u32), OpenAIError> 
+impl OpenAI<AzureConfig> {
+    pub fn new_azure(env: &env::Env) -> Self {
+
+        let client = |deployment_id: String| {
+            let config = AzureConfig::new()
+                .with_api_base(env.config.azure_base())
+                .with_api_key(env.azure_key())
+                .with_api_version(env.config.azure_api_version())
+                .with_deployment_id(deployment_id)
+            ;
+            Api::new(config)
+        };
+
+        Self {
+            chat_client: client(env.config.azure_chat_deployment()),
+            analyse_client: client(env.config.azure_analyse_deployment()),
+            embedding_client: client(env.config.azure_embedding_deployment()),
+            dim: env.config.dim() as u32,
+            chat_model: env.config.chat_model(),
+            analyse_model: env.config.analyse_model(),
+            embedding_model: env.config.embedding_model(),
+            json_schema: env.config.json_schema() && azure_json_schema(&env.config.azure_api_version()),
+        }
+    }
+}
+
+/// azure takes json_schema response formats from 2024-08-01-preview on,
+/// older api versions answer them with a 400
+fn azure_json_schema(api_version: &str) -> bool {
+    api_version.get(..10).is_some_and(|date| date >= "2024-08-01")
+}
+
+fn usage(response: &CreateChatCompletionResponse) -> structs::Usage {
+    match response.usage {
+        None => structs::Usage::default(),
+        Some(ref u) => structs::Usage {
+            prompt_tokens: u.prompt_tokens,
+            completion_tokens: u.completion_tokens,
+        },
+    }
+}
+
+impl<C: Config + Send + Sync> OpenAI<C> {
+    /// one analyse request, held to the schema when the server takes it,
+    /// or to any json object otherwise
+    async fn analyse(&self, prompt: String, name: &str, schema: serde_json::Value)
+        -> Result<(String, structs::Usage), ProviderError>
     {
-        let prompt = prompt_utils::analyse_source_file_prompt(
-            programming_lang, code_string, language
-        );
+        let response_format = if self.json_schema {
+            ResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    description: None,
+                    name: name.to_string(),
+                    schema: Some(schema),
+                    strict: Some(true),
+                }
+            }
+        } else {
+            ResponseFormat::JsonObject
+        };
 
         let request = CreateChatCompletionRequestArgs::default()
             .model(&self.analyse_model)
             .stream(false)
-            .response_format(
-                ChatCompletionResponseFormat {
-                    r#type: ChatCompletionResponseFormatType::JsonObject
-                }
-            )
+            .response_format(response_format)
             .messages([
                 ChatCompletionRequestSystemMessageArgs::default()
-                    .content("As a professional programming expert, analyze the given source code file. Your goal is to thoroughly understand the content and purpose of the code. Your response should be in JSON format.")
+                    .content(prompt_string::ANALYSE_SOURCE_FILE_SYSTEM)
                     .build()?
                     .into(),
                 ChatCompletionRequestUserMessageArgs::default()
@@ -93,52 +235,43 @@ impl OpenAI {
             ])
             .build()?;
 
+        let response = self.analyse_client.create(request).await?;
+        let tokens = usage(&response);
+        let text = response.choices[0].clone().message.content.unwrap_or_default();
+        Ok((text, tokens))
+    }
+}
 
-        let mut n = 0;
-        let mut tokens = 0;
-        let code_description = loop {
+#[async_trait]
+impl<C: Config + Send + Sync> ChatProvider for OpenAI<C> {
 
-            let response = self.client.chat().create(request.clone()).await?;
-            tokens += match response.usage {
-                None => 0,
-                Some(ref u) => {
-                    u.total_tokens
-                }
-            };
-
-            //println!("{:?}", response);
-            let _text = response.choices[0].clone().message.content.unwrap();
-            let text = if _text.starts_with("```json\n"){
-                _text.replace("```json\n", "").replace("```", "")
-            } else {
-                _text
-            };
-            //println!("analyze: {:?}", text);
-            let code_description: structs::GPTResponse = match serde_json::from_str(&text){
-                Ok(c) => c,
-                Err(_) => {
-                    for l in text.split("\n") {
-                        println!("{}", l);
-                    }
-                    panic!("Failed to parse response from OpenAI");
-                    //n += 1;
-                    //if n >= 3 {
-                    //    panic!("Failed to parse response from OpenAI: {:?}", text);
-                    //}
-                    continue;
-                }
-            };
-            break code_description
-        };
-        //println!("code_description: {:?}", code_description);
-        //assert!(tokens < 0, "No tokens used in response");
+    async fn analyse_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<(structs::GPTResponse, structs::Usage), ProviderError> 
+    {
+        let prompt = prompt_utils::analyse_source_file_prompt(
+            programming_lang, code_string, language
+        );
+        json_utils::complete_json(prompt, json_utils::JSON_RETRIES, |prompt| self.analyse(
+            prompt, "code_description", json_utils::analyse_schema()
+        )).await
+    }
 
-        Ok((code_description, tokens))
+    async fn analyse_symbols(&self,
+        code_string: String, programming_lang: String, symbols: Vec<String>, language: String
+    ) -> Result<(structs::GPTSymbolsResponse, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::analyse_symbols_prompt(
+            programming_lang, code_string, symbols, language
+        );
+        json_utils::complete_json(prompt, json_utils::JSON_RETRIES, |prompt| self.analyse(
+            prompt, "symbol_purposes", json_utils::symbols_schema()
+        )).await
     }
 
-    pub async fn split_source(&self,
+    async fn split_source(&self,
         code_string: String, programming_lang: String, language: String
-    ) -> Result<structs::GPTCodeSplitResponse, OpenAIError> 
+    ) -> Result<structs::GPTCodeSplitResponse, ProviderError> 
     {
         let prompt = prompt_utils::split_source_file_prompt(
             programming_lang, code_string, language
@@ -149,7 +282,7 @@ impl OpenAI {
             //.response_format(ChatCompletionResponseFormatType::JsonObject)
             .messages([
                 ChatCompletionRequestSystemMessageArgs::default()
-                    .content("As a professional programming expert, analyze the given source code file. Your response should be in JSON format.")
+                    .content(prompt_string::SPLIT_SOURCE_FILE_SYSTEM)
                     .build()?
                     .into(),
                 ChatCompletionRequestUserMessageArgs::default()
@@ -159,23 +292,20 @@ impl OpenAI {
             ])
             .build()?;
 
-        let response = self.client.chat().create(request).await?;
-        //println!("{:?}", response);
+        let response = self.analyse_client.create(request).await?;
         let _text = response.choices[0].clone().message.content.unwrap();
-        let mut text = String::new();
-        if _text.starts_with("```json\n"){
-            text = _text.replace("```json\n", "").replace("```", "");
+        let text = if _text.starts_with("```json\n") {
+            _text.replace("```json\n", "").replace("```", "")
         } else {
-            text = _text;
-        }
+            _text
+        };
         //println!("split: {:?}", text);
-        let code_description: structs::GPTCodeSplitResponse = serde_json::from_str(&text).unwrap();
-        Ok(code_description)
+        json_utils::parse(&text).map_err(|e| ProviderError::InvalidJson(e.to_string(), structs::Usage::default()))
     }
 
-    pub async fn ask(&self,
+    async fn ask(&self,
         query: String, code_list: Vec<String>, language: String
-    ) -> Result<ChatCompletionResponseStream, OpenAIError> 
+    ) -> Result<AskStream, ProviderError> 
     {
         let prompt = prompt_utils::ask_prompt(
             query, code_list, language
@@ -184,14 +314,10 @@ impl OpenAI {
         let request = CreateChatCompletionRequestArgs::default()
             .model(&self.chat_model)
             .stream(true)
-            .response_format(
-                ChatCompletionResponseFormat {
-                    r#type: ChatCompletionResponseFormatType::Text
-                }
-            )
+            .response_format(ResponseFormat::Text)
             .messages([
                 ChatCompletionRequestSystemMessageArgs::default()
-                    .content("You are a helpful assistant.")
+                    .content(prompt_string::ASSISTANT_SYSTEM)
                     .build()?
                     .into(),
                 ChatCompletionRequestUserMessageArgs::default()
@@ -201,7 +327,7 @@ impl OpenAI {
             ])
             .build()?;
 
-        let response = self.client.chat().create_stream(request).await?;
+        let response = self.chat_client.create_stream(request).await?;
         //let tokens = match response.usage {
         //    None => 0,
         //    Some(ref u) => {
@@ -214,13 +340,13 @@ impl OpenAI {
         Ok(response)
     }
 
-    pub async fn chat(&self, message: String) -> Result<ChatCompletionResponseStream, OpenAIError> {
+    async fn chat(&self, message: String) -> Result<AskStream, ProviderError> {
         let request = CreateChatCompletionRequestArgs::default()
             .model(&self.chat_model)
             .stream(true)
             .messages([
                 ChatCompletionRequestSystemMessageArgs::default()
-                    .content("You are a helpful assistant.")
+                    .content(prompt_string::ASSISTANT_SYSTEM)
                     .build()?
                     .into(),
                 ChatCompletionRequestUserMessageArgs::default()
@@ -230,14 +356,78 @@ impl OpenAI {
             ])
             .build()?;
 
-        let response = self.client.chat().create_stream(request).await?;
+        let response = self.chat_client.create_stream(request).await?;
         Ok(response)
         //Ok(response.choices[0].clone().message.content.unwrap())
     }
 
+    async fn summarize(&self, query: String, language: String) 
+    -> Result<(String, structs::Usage), ProviderError> 
+    {
+        let prompt = prompt_utils::summarize_prompt(
+            query, language
+        );
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&self.chat_model)
+            .stream(false)
+            .response_format(ResponseFormat::Text)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(prompt_string::ASSISTANT_SYSTEM)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(prompt)
+                    .build()?
+                    .into(),
+            ])
+            .build()?;
+
+        let response = self.chat_client.create(request).await?;
+        let tokens = usage(&response);
+        let text = response.choices[0].clone().message.content.unwrap();
+        let text = html_escape::decode_html_entities(&text).to_string();
+        Ok((text, tokens))
+    }
+
+    async fn merge_purposes(&self,
+        parts: Vec<String>, programming_lang: String, language: String
+    ) -> Result<(String, structs::Usage), ProviderError>
+    {
+        let prompt = prompt_utils::merge_purposes_prompt(
+            programming_lang, parts, language
+        );
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&self.analyse_model)
+            .stream(false)
+            .response_format(ResponseFormat::Text)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(prompt_string::ASSISTANT_SYSTEM)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(prompt)
+                    .build()?
+                    .into(),
+            ])
+            .build()?;
 
-    pub async fn embedding_compute(&self, source: Arc<dyn Array>) 
-        -> Result<(Float32Array, u32), OpenAIError> 
+        let response = self.chat_client.create(request).await?;
+        let tokens = usage(&response);
+        let text = response.choices[0].clone().message.content.unwrap();
+        let text = html_escape::decode_html_entities(&text).to_string();
+        Ok((text, tokens))
+    }
+}
+
+#[async_trait]
+impl<C: Config + Send + Sync> EmbeddingProvider for OpenAI<C> {
+
+    async fn embedding_compute(&self, source: Arc<dyn Array>) 
+        -> Result<(Float32Array, u32), ProviderError> 
     {
 
         let input = match source.data_type() {
@@ -266,7 +456,6 @@ impl OpenAI {
             _ => unreachable!("This should not happen. We already checked the data type."),
         };
 
-        let embed = self.client.embeddings();
         let req = CreateEmbeddingRequest {
             model: self.embedding_model.clone(),
             input,
@@ -276,26 +465,9 @@ impl OpenAI {
         };
         //println!("embedding_compute: {:?}", req);
 
-        // TODO: request batching and retry logic
-        //task::block_in_place(move || {
-        //    Handle::current().block_on(async {
-        //        let mut builder = Float32Builder::new();
-
-        //        let res = embed.create(req).await?;
-
-        //        let tokens = res.usage.prompt_tokens;
-
-        //        for Embedding { embedding, .. } in res.data.iter() {
-        //            builder.append_slice(embedding);
-        //        }
-
-        //        Ok((builder.finish(), tokens))
-        //    })
-        //})
-
         let mut builder = Float32Builder::new();
 
-        let res = embed.create(req).await?;
+        let res = self.embedding_client.embeddings(req).await?;
 
         let tokens = res.usage.prompt_tokens;
 
@@ -305,44 +477,17 @@ impl OpenAI {
 
         Ok((builder.finish(), tokens))
     }
+}
 
-    pub async fn summarize(&self, query: String, language: String) 
-    -> Result<(String, u32), OpenAIError> 
-    {
-        let prompt = prompt_utils::summarize_prompt(
-            query, language
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(&self.chat_model)
-            .stream(false)
-            .response_format(
-                ChatCompletionResponseFormat {
-                    r#type: ChatCompletionResponseFormatType::Text
-                }
-            )
-            .messages([
-                ChatCompletionRequestSystemMessageArgs::default()
-                    .content("You are a helpful assistant.")
-                    .build()?
-                    .into(),
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content(prompt)
-                    .build()?
-                    .into(),
-            ])
-            .build()?;
-
-        let response = self.client.chat().create(request).await?;
-        //println!("{:?}", response);
-        let tokens = match response.usage {
-            None => 0,
-            Some(ref u) => {
-                u.total_tokens
-            }
-        };
-        let text = response.choices[0].clone().message.content.unwrap();
-        let text = html_escape::decode_html_entities(&text).to_string();
-        Ok((text, tokens))
+    #[test]
+    fn azure_api_versions() {
+        assert!(azure_json_schema("2024-08-01-preview"));
+        assert!(azure_json_schema("2024-10-21"));
+        assert!(!azure_json_schema("2024-02-15-preview"));
+        assert!(!azure_json_schema("latest"));
     }
 }
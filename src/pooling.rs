@@ -4,33 +4,29 @@ use arrow_array::{
     PrimitiveArray,
 };
 
-use crate::env;
-
+/// `input` holds several `dim` sized vectors one after another,
+/// gives their element-wise mean, normalized to length 1 like the model's own vectors
 pub fn pooling(dim: usize, input: &PrimitiveArray<Float32Type>) -> PrimitiveArray<Float32Type> {
 
-    let stride = input.len()/dim;
-
-    let input_width = input.len(); // 输入特征图的宽度
-    //
-    let  pool_width = stride;
+    let count = input.len()/dim;
 
-    let output_width = (input_width - pool_width) / stride + 1;
-
-    let mut output = vec![0.0; output_width];
+    let mut output = vec![0.0; dim];
 
     // 执行平均池化操作
-    for i in 0..output_width {
-        // 计算当前池化窗口的起始坐标
-        let start_i = i * stride;
-
-        // 提取池化窗口内的元素并计算平均值
-        let mut sum = 0.0;
-        for m in 0..pool_width {
-            sum += input.value(start_i + m);
+    for n in 0..count {
+        for (i, o) in output.iter_mut().enumerate() {
+            *o += input.value(n * dim + i);
         }
+    }
+    for o in output.iter_mut() {
+        *o /= count as f32;
+    }
 
-        // 计算池化窗口的平均值并赋值给输出特征图
-        output[i] = sum / pool_width as f32;
+    let norm = output.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for o in output.iter_mut() {
+            *o /= norm;
+        }
     }
     
     PrimitiveArray::<Float32Type>::from_iter_values(output.iter().copied())
@@ -1,3 +1,16 @@
+/// bump when ANALYSE_SOURCE_FILE, ANALYSE_SYMBOLS, MERGE_PART_PURPOSES or their system prompt changes, cached analyses are keyed by it
+pub const PROMPT_VERSION: &str = "3";
+
+pub const ANALYSE_SOURCE_FILE_SYSTEM: &str = "As a professional programming expert, analyze the given source code file. Your goal is to thoroughly understand the content and purpose of the code. Your response should be in JSON format.";
+
+#[allow(dead_code)]
+pub const SPLIT_SOURCE_FILE_SYSTEM: &str = "As a professional programming expert, analyze the given source code file. Your response should be in JSON format.";
+
+pub const ASSISTANT_SYSTEM: &str = "You are a helpful assistant.";
+
+////////////////////////
+
+#[allow(dead_code)]
 pub const SPLIT_SOURCE_FILE: &str = "I have the following code in {{ programming_language }}:
 
 ```
@@ -36,7 +49,7 @@ pub const CHAT_WITH_RELATED_SOURCE_FILES: &str = "Here is the user's query:
 {{ query }}
 ```
 
-Understand the user's query and explain with the related description and source code:
+Understand the user's query and explain with the related description, source code and documentation:
 
 {% for item in content_list %}
 {{ item}}
@@ -85,6 +98,56 @@ This JSON output will help us understand the structure and functionality of the
 ////////////////////////
 
 
+pub const FIX_JSON: &str = "{{ prompt }}
+
+Your last answer was:
+
+```
+{{ answer }}
+```
+
+It is not valid JSON: {{ error }}
+
+Please answer again, with ONLY the JSON data, and MAKE SURE it is VALID.
+";
+
+////////////////////////
+
+pub const ANALYSE_SYMBOLS: &str = "I have the following code in {{ programming_language }}:
+
+```
+{{ code }}
+```
+
+It defines these classes and functions:
+
+{% for item in symbols %}
+{{ loop.index0 }}. {{ item }}
+{% endfor %}
+
+Could you please explain what this code does, and what each of the classes and functions above is doing?
+
+Make sure the JSON output is structured as follows:
+
+```
+{
+  \"purpose\": \"string\", // what this code is doing
+  \"symbols\": [
+    {
+      \"id\": 0, // the number of the class or function above
+      \"purpose\": \"string\" // what this class or function is doing
+    }
+  ]
+}
+```
+
+No need to give the source code back. PLEASE give me a JSON data follow above structure, and MAKE SURE the JSON data is VALID.
+";
+
+////////////////////////
+
+
+#[allow(dead_code)]
 pub const CHAT_WITH_MODEL: &str = "Here is the user's query:
 
 ```
@@ -96,6 +159,7 @@ Understand the user's query and give a reasonable response.
 
 ////////////////////////
 
+#[allow(dead_code)]
 pub const GET_RELATED_SOURCE_FILES: &str = "Here is the user's query:
 
 ```
@@ -130,6 +194,17 @@ Make sure the JSON output is structured as follows:
 ```
 ";
 
+////////////////////////
+
+pub const MERGE_PART_PURPOSES: &str = "I have a source file in {{ programming_language }} that is too large to read at once, so it was explained in parts. Here is what each part does:
+
+{% for item in parts %}
+{{ item }}
+{% endfor %}
+
+Could you please explain in a few sentences what the whole file does? Only give the explanation, no headings or lists of the parts.
+";
+
 ////////////////////////
 pub const SUMMARIZE_PROJECT: &str = "Here is a project summary:
 ```
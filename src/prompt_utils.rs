@@ -15,6 +15,21 @@ pub fn analyse_source_file_prompt(programming_language: String, code: String, la
     format!("{}\n\nMake sure all the output contents are in {}.", p, language)
 }
 
+/// the symbols are found by the parser, the model only says what they do
+pub fn analyse_symbols_prompt(programming_language: String, code: String, symbols: Vec<String>, language: String) -> String {
+    let mut env = Environment::new();
+    env.add_template("t", prompt_string::ANALYSE_SYMBOLS).unwrap();
+    let tmpl = env.get_template("t").unwrap();
+    let p = tmpl.render(context!(
+        programming_language => programming_language,
+        code => code,
+        symbols => symbols,
+    )).unwrap();
+
+    format!("{}\n\nMake sure all the output contents are in {}.", p, language)
+}
+
+#[allow(dead_code)]
 pub fn split_source_file_prompt(programming_language: String, code: String, language: String) -> String {
     let mut env = Environment::new();
     env.add_template("t", prompt_string::SPLIT_SOURCE_FILE).unwrap();
@@ -27,6 +42,18 @@ pub fn split_source_file_prompt(programming_language: String, code: String, lang
     format!("{}\n\nMake sure all the output contents are in {}.", p, language)
 }
 
+/// the prompt again, with the answer that did not parse and why
+pub fn fix_json_prompt(prompt: String, answer: String, error: String) -> String {
+    let mut env = Environment::new();
+    env.add_template("t", prompt_string::FIX_JSON).unwrap();
+    let tmpl = env.get_template("t").unwrap();
+    tmpl.render(context!(
+        prompt => prompt,
+        answer => answer,
+        error => error,
+    )).unwrap()
+}
+
 pub fn ask_prompt(query: String, code_list: Vec<String>, language: String) -> String {
     let mut env = Environment::new();
     env.add_template("t", prompt_string::CHAT_WITH_RELATED_SOURCE_FILES).unwrap();
@@ -39,6 +66,19 @@ pub fn ask_prompt(query: String, code_list: Vec<String>, language: String) -> St
     format!("{}\n\nMake sure all the output contents are in {}.", p, language)
 }
 
+/// the purposes of the parts of a file too large to analyse at once
+pub fn merge_purposes_prompt(programming_language: String, parts: Vec<String>, language: String) -> String {
+    let mut env = Environment::new();
+    env.add_template("t", prompt_string::MERGE_PART_PURPOSES).unwrap();
+    let tmpl = env.get_template("t").unwrap();
+    let p = tmpl.render(context!(
+        programming_language => programming_language,
+        parts => parts,
+    )).unwrap();
+
+    format!("{}\n\nMake sure all the output contents are in {}.", p, language)
+}
+
 pub fn summarize_prompt(query: String, language: String) -> String{
     let mut env = Environment::new();
     env.add_template("t", prompt_string::SUMMARIZE_PROJECT).unwrap();
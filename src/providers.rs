@@ -0,0 +1,363 @@
+use std::fmt;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arrow::array::Array;
+use arrow_schema::DataType;
+use arrow_array::{
+    cast::AsArray,
+    Float32Array,
+};
+
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+
+use async_openai::error::OpenAIError;
+
+use crate::structs;
+use crate::env;
+use crate::openai_utils::OpenAI;
+use crate::local_embedding_utils::LocalEmbedding;
+use crate::ollama_utils::Ollama;
+use crate::llamacpp_utils::LlamaCpp;
+use crate::cassette_utils::{Cassette, CassetteChat, CassetteEmbedding};
+use crate::retry_utils::{self, RetryChat, RetryEmbedding, RetryPolicy};
+use crate::usage_utils::{LedgerChat, LedgerEmbedding};
+
+/// text pieces of a streaming answer
+pub type AskStream = Pin<Box<dyn Stream<Item = Result<String, ProviderError>> + Send>>;
+
+#[derive(Debug)]
+pub enum ProviderError {
+    OpenAI(OpenAIError),
+    /// the input is longer than the model's context window
+    ContextLength(String),
+    /// a non 2xx answer from a backend we talk to with reqwest
+    Http {
+        status: u16,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// the request never got an answer, connection refused, timeout...
+    Transport(String),
+    /// the answer did not parse, even after repairing and asking again.
+    /// the tokens of every attempt are spent all the same
+    InvalidJson(String, structs::Usage),
+    /// a provider the config names does not exist or does not load
+    Config(String),
+    Other(String),
+}
+
+impl ProviderError {
+    /// rate limits, server errors and network trouble are worth another try
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ProviderError::OpenAI(e) => match e {
+                OpenAIError::Reqwest(_) => true,
+                OpenAIError::StreamError(_) => true,
+                OpenAIError::JSONDeserialize(_) => true,
+                OpenAIError::ApiError(ee) => {
+                    let detail = format!("{:?} {:?} {}", ee.r#type, ee.code, ee.message).to_lowercase();
+                    !detail.contains("insufficient_quota") && (
+                        detail.contains("rate_limit")
+                        || detail.contains("rate limit")
+                        || detail.contains("server_error")
+                        || detail.contains("overloaded")
+                        || detail.contains("timeout")
+                    )
+                },
+                _ => false,
+            },
+            // openai answers an empty account with a 429 too
+            ProviderError::Http { status, message, .. } =>
+                (*status == 429 && !message.contains("insufficient_quota")) || *status >= 500,
+            ProviderError::Transport(_) => true,
+            ProviderError::ContextLength(_)
+                | ProviderError::InvalidJson(..)
+                | ProviderError::Config(_)
+                | ProviderError::Other(_) => false,
+        }
+    }
+
+    /// how long the server asked us to wait
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ProviderError::Http { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::OpenAI(e) => write!(f, "{}", e),
+            ProviderError::ContextLength(m) => write!(f, "context length exceeded: {}", m),
+            ProviderError::Http { status, message, .. } => write!(f, "{} {}", status, message),
+            ProviderError::Transport(m) => write!(f, "{}", m),
+            ProviderError::InvalidJson(m, _) => write!(f, "invalid json: {}", m),
+            ProviderError::Config(m) => write!(f, "invalid config: {}", m),
+            ProviderError::Other(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<OpenAIError> for ProviderError {
+    fn from(e: OpenAIError) -> Self {
+        match e {
+            OpenAIError::ApiError(ref ee) if ee.message.contains(
+                "This model's maximum context length"
+            ) => ProviderError::ContextLength(ee.message.clone()),
+            _ => ProviderError::OpenAI(e),
+        }
+    }
+}
+
+/// backend used to analyse source code and answer questions
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn analyse_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<(structs::GPTResponse, structs::Usage), ProviderError>;
+
+    /// only the purposes, of the file and of the symbols the parser found
+    async fn analyse_symbols(&self,
+        code_string: String, programming_lang: String, symbols: Vec<String>, language: String
+    ) -> Result<(structs::GPTSymbolsResponse, structs::Usage), ProviderError>;
+
+    #[allow(dead_code)]
+    async fn split_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<structs::GPTCodeSplitResponse, ProviderError>;
+
+    async fn ask(&self,
+        query: String, code_list: Vec<String>, language: String
+    ) -> Result<AskStream, ProviderError>;
+
+    #[allow(dead_code)]
+    async fn chat(&self, message: String) -> Result<AskStream, ProviderError>;
+
+    async fn summarize(&self, query: String, language: String)
+        -> Result<(String, structs::Usage), ProviderError>;
+
+    /// one purpose for a file analysed in parts, on the analyse model
+    async fn merge_purposes(&self,
+        parts: Vec<String>, programming_lang: String, language: String
+    ) -> Result<(String, structs::Usage), ProviderError>;
+}
+
+/// backend used to compute the vectors stored in the `vectors` table
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// one `dim` sized vector per input string, flattened
+    async fn embedding_compute(&self, source: Arc<dyn Array>)
+        -> Result<(Float32Array, u32), ProviderError>;
+}
+
+/// the chat and embedding backends selected in `config.yaml`
+pub struct Providers {
+    pub chat: Box<dyn ChatProvider>,
+    pub embedding: Box<dyn EmbeddingProvider>,
+}
+
+impl Providers {
+    pub fn new(env: &env::Env) -> Result<Self, ProviderError> {
+        let cassette_path = Path::new(&env.temp_dir).join("cassette.jsonl");
+        let cassette_error = |e: std::io::Error| ProviderError::Other(
+            format!("can not open cassette {:?}, {}", cassette_path, e)
+        );
+        Ok(match env.config.cassette().as_str() {
+            "record" => {
+                let cassette = Arc::new(Cassette::record(cassette_path.as_path()).map_err(cassette_error)?);
+                Self {
+                    chat: Box::new(CassetteChat::new(Some(retry_chat_provider(env)?), cassette.clone())),
+                    embedding: Box::new(CassetteEmbedding::new(Some(retry_embedding_provider(env)?), cassette)),
+                }
+            },
+            "replay" => {
+                let cassette = Arc::new(Cassette::replay(cassette_path.as_path()).map_err(cassette_error)?);
+                Self {
+                    chat: Box::new(CassetteChat::new(None, cassette.clone())),
+                    embedding: Box::new(CassetteEmbedding::new(None, cassette)),
+                }
+            },
+            _ => Self {
+                chat: retry_chat_provider(env)?,
+                embedding: retry_embedding_provider(env)?,
+            },
+        })
+    }
+}
+
+/// retried, and the usage of every call written to the ledger
+fn retry_chat_provider(env: &env::Env) -> Result<Box<dyn ChatProvider>, ProviderError> {
+    Ok(Box::new(LedgerChat::new(
+        Box::new(RetryChat::new(chat_provider(env)?, RetryPolicy::new(env))),
+        env
+    )))
+}
+
+fn retry_embedding_provider(env: &env::Env) -> Result<Box<dyn EmbeddingProvider>, ProviderError> {
+    Ok(Box::new(LedgerEmbedding::new(
+        Box::new(RetryEmbedding::new(embedding_provider(env)?, RetryPolicy::new(env))),
+        env
+    )))
+}
+
+fn chat_provider(env: &env::Env) -> Result<Box<dyn ChatProvider>, ProviderError> {
+    Ok(match env.config.chat_provider().as_str() {
+        "openai" => Box::new(OpenAI::new(env)),
+        "azure" => Box::new(OpenAI::new_azure(env)),
+        "ollama" => Box::new(Ollama::new(env)),
+        "llamacpp" => Box::new(LlamaCpp::new(env)),
+        p => return Err(ProviderError::Config(format!(
+            "unknown chat_provider {}, it is one of openai, azure, ollama, llamacpp", p
+        ))),
+    })
+}
+
+fn embedding_provider(env: &env::Env) -> Result<Box<dyn EmbeddingProvider>, ProviderError> {
+    Ok(match env.config.embedding_provider().as_str() {
+        "openai" => Box::new(OpenAI::new(env)),
+        "azure" => Box::new(OpenAI::new_azure(env)),
+        "local" => match LocalEmbedding::new(env) {
+            Ok(e) => Box::new(e),
+            Err(e) => return Err(ProviderError::Config(
+                format!("failed to load the local embedding model, {}", e)
+            )),
+        },
+        p => return Err(ProviderError::Config(format!(
+            "unknown embedding_provider {}, it is one of openai, azure, local", p
+        ))),
+    })
+}
+
+/// the strings of a Utf8 or LargeUtf8 array
+pub fn array_strings(source: &Arc<dyn Array>) -> Vec<String> {
+    match source.data_type() {
+        DataType::Utf8 => source
+            .as_string::<i32>()
+            .into_iter()
+            .map(|s| s.unwrap_or("").to_string())
+            .collect::<Vec<String>>(),
+        DataType::LargeUtf8 => source
+            .as_string::<i64>()
+            .into_iter()
+            .map(|s| s.unwrap_or("").to_string())
+            .collect::<Vec<String>>(),
+        _ => unreachable!("This should not happen. We already checked the data type."),
+    }
+}
+
+/// post a json body, non 2xx answers become errors
+pub async fn post_json(
+    client: &reqwest::Client, url: String, body: &serde_json::Value
+) -> Result<reqwest::Response, ProviderError> {
+    let response = client.post(&url)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| ProviderError::Transport(format!("{}: {}", url, e)))?;
+    check_status(&url, response).await
+}
+
+/// a non 2xx answer becomes an error, with the wait the server asked for
+pub async fn check_status(url: &str, response: reqwest::Response) -> Result<reqwest::Response, ProviderError> {
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = retry_utils::retry_after(response.headers());
+        let message = response.text().await.unwrap_or_default();
+        return Err(ProviderError::Http {
+            status: status.as_u16(),
+            message: format!("{}: {}", url, message),
+            retry_after,
+        });
+    }
+    Ok(response)
+}
+
+/// split a streaming http body into lines
+pub fn response_lines(response: reqwest::Response) -> AskStream {
+    let lines = stream::unfold(
+        (Box::pin(response.bytes_stream()), Vec::<u8>::new()),
+        |(mut bytes, mut buffer)| async move {
+            loop {
+                if let Some(i) = buffer.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=i).collect();
+                    let line = String::from_utf8_lossy(&line).trim().to_string();
+                    return Some((Ok(line), (bytes, buffer)));
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        buffer.clear();
+                        return Some((Err(ProviderError::Transport(e.to_string())), (bytes, buffer)));
+                    },
+                    None => {
+                        if buffer.is_empty() {
+                            return None;
+                        }
+                        let line = String::from_utf8_lossy(&buffer).trim().to_string();
+                        buffer.clear();
+                        return Some((Ok(line), (bytes, buffer)));
+                    }
+                }
+            }
+        }
+    );
+    Box::pin(lines)
+}
+
+/// a one shot http server for the tests of the backends: answers the first
+/// request with `status` and a body sent in the given chunks, one write each,
+/// so frames can arrive cut in the middle. gives the base url and the body
+/// of the request it got
+#[cfg(test)]
+pub fn stand_in_server(status: u16, content_type: &'static str, chunks: Vec<&'static str>)
+    -> (String, std::thread::JoinHandle<serde_json::Value>)
+{
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 {} X\r\ncontent-type: {}\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n",
+            status, content_type
+        ).unwrap();
+        for chunk in chunks {
+            write!(stream, "{:x}\r\n{}\r\n", chunk.len(), chunk).unwrap();
+            stream.flush().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        write!(stream, "0\r\n\r\n").unwrap();
+        serde_json::from_slice(&body).unwrap()
+    });
+    (base, handle)
+}
@@ -0,0 +1,306 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arrow::array::Array;
+use arrow_array::Float32Array;
+
+use async_trait::async_trait;
+use futures::{future, stream, StreamExt};
+
+use rand::Rng;
+
+use reqwest::header::HeaderMap;
+
+use crate::structs;
+
+use crate::env;
+
+use crate::providers::{
+    AskStream,
+    ChatProvider,
+    EmbeddingProvider,
+    ProviderError,
+};
+
+/// how often and how long to wait before giving up on a request
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    retries: u32,
+    delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(env: &env::Env) -> Self {
+        Self {
+            retries: env.config.retries(),
+            delay: Duration::from_millis(env.config.retry_delay_ms()),
+            max_delay: Duration::from_millis(env.config.retry_max_delay_ms()),
+        }
+    }
+
+    /// exponential backoff with jitter, between half and all of delay * 2^attempt
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// how long to wait before the next attempt, None to give up
+    fn wait(&self, attempt: u32, error: &ProviderError) -> Option<Duration> {
+        if attempt >= self.retries || !error.is_retryable() {
+            return None;
+        }
+        match error.retry_after() {
+            // no longer than we are willing to, the next attempt tells if it was enough
+            Some(d) => Some(d.min(self.max_delay)),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+}
+
+/// run `f` until it succeeds, fails for good or the retry budget is spent
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, what: &str, mut f: F) -> Result<T, ProviderError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ProviderError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(r) => return Ok(r),
+            Err(e) => {
+                let delay = match policy.wait(attempt, &e) {
+                    None => return Err(e),
+                    Some(d) => d,
+                };
+                println!("{} failed, retry in {:?}: {}", what, delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// like `retry`, a stream that fails before its first piece is opened again
+pub async fn retry_stream<F, Fut>(policy: &RetryPolicy, what: &str, mut f: F) -> Result<AskStream, ProviderError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<AskStream, ProviderError>>,
+{
+    retry(policy, what, || {
+        let stream = f();
+        async move {
+            let mut stream = stream.await?;
+            match stream.next().await {
+                None => Ok(Box::pin(stream::empty()) as AskStream),
+                Some(Err(e)) => Err(e),
+                Some(Ok(first)) => Ok(
+                    Box::pin(stream::once(future::ready(Ok(first))).chain(stream)) as AskStream
+                ),
+            }
+        }
+    }).await
+}
+
+/// `Retry-After` in seconds when the server sends it, otherwise the
+/// longest of openai's `x-ratelimit-reset-*` like "6m0s"
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_duration);
+    header("retry-after").or_else(|| {
+        ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+            .iter()
+            .filter_map(|name| header(name))
+            .max()
+    })
+}
+
+/// longer than anyone waits, RetryPolicy cuts it down to max_delay anyway
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 3600);
+
+/// "inf" and huge numbers are the longest wait, "NaN" is no duration
+fn seconds(seconds: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(seconds.clamp(0.0, MAX_RETRY_AFTER.as_secs_f64())).ok()
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    if let Ok(n) = s.parse::<f64>() {
+        return seconds(n);
+    }
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let value: f64 = number.parse().ok()?;
+        number.clear();
+        let unit = match c {
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                0.001
+            },
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return None,
+        };
+        total += value * unit;
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    seconds(total)
+}
+
+/// retries the wrapped chat provider
+pub struct RetryChat {
+    inner: Box<dyn ChatProvider>,
+    policy: RetryPolicy,
+}
+
+impl RetryChat {
+    pub fn new(inner: Box<dyn ChatProvider>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl ChatProvider for RetryChat {
+
+    async fn analyse_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<(structs::GPTResponse, structs::Usage), ProviderError>
+    {
+        retry(&self.policy, "analyse_source", || self.inner.analyse_source(
+            code_string.clone(), programming_lang.clone(), language.clone()
+        )).await
+    }
+
+    async fn analyse_symbols(&self,
+        code_string: String, programming_lang: String, symbols: Vec<String>, language: String
+    ) -> Result<(structs::GPTSymbolsResponse, structs::Usage), ProviderError>
+    {
+        retry(&self.policy, "analyse_symbols", || self.inner.analyse_symbols(
+            code_string.clone(), programming_lang.clone(), symbols.clone(), language.clone()
+        )).await
+    }
+
+    async fn split_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<structs::GPTCodeSplitResponse, ProviderError>
+    {
+        retry(&self.policy, "split_source", || self.inner.split_source(
+            code_string.clone(), programming_lang.clone(), language.clone()
+        )).await
+    }
+
+    async fn ask(&self,
+        query: String, code_list: Vec<String>, language: String
+    ) -> Result<AskStream, ProviderError>
+    {
+        retry_stream(&self.policy, "ask", || self.inner.ask(
+            query.clone(), code_list.clone(), language.clone()
+        )).await
+    }
+
+    async fn chat(&self, message: String) -> Result<AskStream, ProviderError> {
+        retry_stream(&self.policy, "chat", || self.inner.chat(message.clone())).await
+    }
+
+    async fn summarize(&self, query: String, language: String)
+        -> Result<(String, structs::Usage), ProviderError>
+    {
+        retry(&self.policy, "summarize", || self.inner.summarize(
+            query.clone(), language.clone()
+        )).await
+    }
+
+    async fn merge_purposes(&self,
+        parts: Vec<String>, programming_lang: String, language: String
+    ) -> Result<(String, structs::Usage), ProviderError>
+    {
+        retry(&self.policy, "merge_purposes", || self.inner.merge_purposes(
+            parts.clone(), programming_lang.clone(), language.clone()
+        )).await
+    }
+}
+
+/// retries the wrapped embedding provider
+pub struct RetryEmbedding {
+    inner: Box<dyn EmbeddingProvider>,
+    policy: RetryPolicy,
+}
+
+impl RetryEmbedding {
+    pub fn new(inner: Box<dyn EmbeddingProvider>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for RetryEmbedding {
+
+    async fn embedding_compute(&self, source: Arc<dyn Array>)
+        -> Result<(Float32Array, u32), ProviderError>
+    {
+        retry(&self.policy, "embedding_compute", || self.inner.embedding_compute(
+            source.clone()
+        )).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_seconds() {
+        assert_eq!(parse_duration("20"), Some(Duration::from_secs(20)));
+        assert_eq!(parse_duration(" 0.5 "), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("-3"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn parse_out_of_range() {
+        assert_eq!(parse_duration("inf"), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_duration("1e300"), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_duration("99999999999999999999h"), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_duration("-inf"), Some(Duration::ZERO));
+        assert_eq!(parse_duration("NaN"), None);
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_duration("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_duration("120ms"), Some(Duration::from_millis(120)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse_duration("12x"), None);
+        assert_eq!(parse_duration("5m3"), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[test]
+    fn retry_after_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert("x-ratelimit-reset-requests", "1s".parse().unwrap());
+        headers.insert("x-ratelimit-reset-tokens", "6m0s".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(360)));
+        headers.insert("retry-after", "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+    }
+}
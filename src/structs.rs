@@ -1,6 +1,7 @@
 
+use std::ops::AddAssign;
+
 use serde::{Deserialize, Serialize};
-use serde_json;
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,17 +12,47 @@ pub struct CodeDescription {
     pub purpose: String,
     pub lang: Option<String>,
     pub file: Option<String>,
-    pub code_type: Option<String>, // "file", "class", "function"
+    pub code_type: Option<String>, // "file", "class", "function", "doc", "failed"
+    pub start_line: Option<usize>, // 1 based, inclusive
+    pub end_line: Option<usize>,
+    pub start_byte: Option<usize>,
+    pub end_byte: Option<usize>,
 }
 
+/// one file of the project on its way into the index
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub lang: String,   // the programming language, or the doc format
+    pub path: String,   // relative to the project
+    pub md5: String,
+    pub content: String,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GPTResponse {
     pub purpose: String,
+    #[serde(default)]
     pub classes: Vec<CodeDescription>,
+    #[serde(default)]
     pub functions: Vec<CodeDescription>,
 }
 
+/// what a symbol found by the parser does, `id` is its place in the list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SymbolPurpose {
+    pub id: usize,
+    pub purpose: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GPTSymbolsResponse {
+    pub purpose: String,
+    #[serde(default)]
+    pub symbols: Vec<SymbolPurpose>,
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodeSplit {
     pub name: String,
@@ -30,9 +61,30 @@ pub struct CodeSplit {
 }
 
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GPTCodeSplitResponse {
     pub classes: Vec<CodeSplit>,
     pub functions: Vec<CodeSplit>,
 }
 
+
+/// tokens one call to a model used
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl Usage {
+    pub fn total(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
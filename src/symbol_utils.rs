@@ -0,0 +1,294 @@
+use tree_sitter::{Language, Node, Parser};
+
+/// a class or function found by the parser, with where it is in the file
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub code_type: String,  // "class", "function"
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub start_line: usize,  // 1 based
+    pub end_line: usize,    // inclusive
+    pub source_code: String,
+}
+
+impl Symbol {
+    /// how the symbol is shown to the model, `function foo (lines 3-9)`
+    pub fn describe(&self) -> String {
+        format!("{} {} (lines {}-{})", self.code_type, self.name, self.start_line, self.end_line)
+    }
+}
+
+/// the line of a byte offset, 1 based
+pub fn line_of(code: &str, byte: usize) -> usize {
+    code.as_bytes()[..byte.min(code.len())].iter().filter(|b| **b == b'\n').count() + 1
+}
+
+/// (start byte, end byte, start line, end line) of the first exact copy of
+/// `snippet` in `code`, for the symbols the model found
+pub fn locate(code: &str, snippet: &str) -> Option<(usize, usize, usize, usize)> {
+    if snippet.trim().is_empty() {
+        return None;
+    }
+    let start = code.find(snippet)?;
+    let end = start + snippet.len();
+    Some((start, end, line_of(code, start), line_of(code, end.saturating_sub(1).max(start))))
+}
+
+/// cut a file into parts of at most `max_tokens` tokens, between top level
+/// symbols when the parser found them, else before lines starting at column 0.
+/// a piece between two cuts that is still too large is cut at every line.
+/// gives the byte ranges of the parts
+pub fn split(
+    code: &str, symbols: Option<&[Symbol]>, max_tokens: usize, count: impl Fn(&str) -> usize
+) -> Vec<(usize, usize)> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(code.match_indices('\n').map(|(i, _)| i + 1))
+        .filter(|i| *i < code.len())
+        .collect();
+    // the line a byte is in, and the first line starting at or after it
+    let line_start = |byte: usize| line_starts[line_starts.partition_point(|s| *s <= byte).max(1) - 1];
+    let next_line = |byte: usize| line_starts
+        .get(line_starts.partition_point(|s| *s < byte))
+        .copied()
+        .unwrap_or(code.len());
+
+    let mut cuts: Vec<usize> = match symbols {
+        Some(symbols) => {
+            let mut cuts = Vec::new();
+            let mut end = 0;
+            for s in symbols.iter() {
+                // methods and other nested symbols stay with their parent
+                if s.start_byte < end {
+                    continue;
+                }
+                cuts.push(line_start(s.start_byte));
+                cuts.push(next_line(s.end_byte));
+                end = s.end_byte;
+            }
+            cuts
+        },
+        None => line_starts.iter()
+            .filter(|i| code[**i..].starts_with(|c: char| !c.is_whitespace()))
+            .copied()
+            .collect(),
+    };
+    cuts.push(0);
+    cuts.push(code.len());
+    cuts.sort();
+    cuts.dedup();
+
+    // (start, end, tokens)
+    let mut pieces: Vec<(usize, usize, usize)> = Vec::new();
+    for w in cuts.windows(2) {
+        let (a, b) = (w[0], w[1]);
+        let tokens = count(&code[a..b]);
+        if tokens <= max_tokens {
+            pieces.push((a, b, tokens));
+            continue;
+        }
+        let mut prev = a;
+        for l in line_starts.iter().copied().filter(|l| *l > a && *l < b).chain(std::iter::once(b)) {
+            pieces.push((prev, l, count(&code[prev..l])));
+            prev = l;
+        }
+    }
+
+    let mut parts = Vec::new();
+    let (mut start, mut end, mut tokens) = (0, 0, 0);
+    for (a, b, t) in pieces {
+        if end > start && tokens + t > max_tokens {
+            parts.push((start, end));
+            start = a;
+            tokens = 0;
+        }
+        end = b;
+        tokens += t;
+    }
+    if end > start {
+        parts.push((start, end));
+    }
+    parts
+}
+
+/// the grammar of a language of language_extensions, None when there is none
+fn grammar(programming_lang: &str, file: &str) -> Option<Language> {
+    let language = match programming_lang {
+        "Python" => tree_sitter_python::LANGUAGE,
+        "Rust" => tree_sitter_rust::LANGUAGE,
+        "JavaScript" => tree_sitter_javascript::LANGUAGE,
+        "TypeScript" => if file.ends_with(".tsx") {
+            tree_sitter_typescript::LANGUAGE_TSX
+        } else {
+            tree_sitter_typescript::LANGUAGE_TYPESCRIPT
+        },
+        "Go" => tree_sitter_go::LANGUAGE,
+        "Java" => tree_sitter_java::LANGUAGE,
+        "C" => tree_sitter_c::LANGUAGE,
+        "C++" => tree_sitter_cpp::LANGUAGE,
+        "C#" => tree_sitter_c_sharp::LANGUAGE,
+        "Ruby" => tree_sitter_ruby::LANGUAGE,
+        _ => return None,
+    };
+    Some(language.into())
+}
+
+/// whether a node of the grammar is a class or a function
+fn code_type(kind: &str) -> Option<&'static str> {
+    match kind {
+        // python, rust, js/ts, go, java, c/c++, c#, ruby
+        "class_definition"
+        | "struct_item" | "enum_item" | "union_item" | "trait_item" | "impl_item"
+        | "class_declaration" | "abstract_class_declaration" | "interface_declaration"
+        | "enum_declaration" | "record_declaration" | "struct_declaration" | "trait_declaration"
+        | "type_declaration"
+        | "struct_specifier" | "union_specifier" | "enum_specifier" | "class_specifier"
+        | "class" | "module" => Some("class"),
+        "function_definition" | "function_item"
+        | "function_declaration" | "generator_function_declaration" | "method_definition"
+        | "method_declaration" | "constructor_declaration"
+        | "method" | "singleton_method" => Some("function"),
+        _ => None,
+    }
+}
+
+/// the name of a symbol node, None for anonymous ones and declarations without a body
+fn symbol_name(node: Node, source: &[u8]) -> Option<String> {
+    let text = |n: Node| n.utf8_text(source).ok().map(|s| s.to_string());
+    match node.kind() {
+        // `impl Foo for Bar` is named after Bar
+        "impl_item" => node.child_by_field_name("type").and_then(text),
+        // `type Foo struct {...}`, the name is in the type_spec
+        "type_declaration" => {
+            let mut cursor = node.walk();
+            let spec = node.named_children(&mut cursor)
+                .find(|c| c.kind() == "type_spec" || c.kind() == "type_alias");
+            spec.and_then(|s| s.child_by_field_name("name")).and_then(text)
+        },
+        // `struct foo;` or `struct foo x;` is not a definition
+        "struct_specifier" | "union_specifier" | "enum_specifier" | "class_specifier" => {
+            node.child_by_field_name("body")?;
+            node.child_by_field_name("name").and_then(text)
+        },
+        // c and c++ hide the name in nested declarators, `int *foo(int a)`
+        "function_definition" if node.child_by_field_name("name").is_none() => {
+            let mut d = node.child_by_field_name("declarator")?;
+            while let Some(inner) = d.child_by_field_name("declarator") {
+                d = inner;
+            }
+            text(d)
+        },
+        _ => node.child_by_field_name("name").and_then(text),
+    }
+}
+
+fn collect(node: Node, source: &[u8], symbols: &mut Vec<Symbol>) {
+    if let Some(code_type) = code_type(node.kind()) {
+        if let Some(name) = symbol_name(node, source) {
+            symbols.push(Symbol {
+                name,
+                code_type: code_type.to_string(),
+                start_byte: node.start_byte(),
+                end_byte: node.end_byte(),
+                start_line: node.start_position().row + 1,
+                end_line: node.end_position().row + 1,
+                source_code: String::from_utf8_lossy(
+                    &source[node.start_byte()..node.end_byte()]
+                ).to_string(),
+            });
+        }
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        collect(child, source, symbols);
+    }
+}
+
+/// the classes and functions of a file in source order, methods included,
+/// None when the language has no grammar or the file does not parse
+pub fn extract(programming_lang: &str, file: &str, code: &str) -> Option<Vec<Symbol>> {
+    let language = grammar(programming_lang, file)?;
+    let mut parser = Parser::new();
+    parser.set_language(&language).ok()?;
+    let tree = parser.parse(code, None)?;
+
+    let mut symbols = Vec::new();
+    collect(tree.root_node(), code.as_bytes(), &mut symbols);
+    Some(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = "import os\n\ndef a():\n    x = 1\n    return x\n\ndef b():\n    return 2\n\nclass C:\n    def m(self):\n        pass\n";
+
+    fn lines(text: &str) -> usize {
+        text.lines().count()
+    }
+
+    #[test]
+    fn split_between_symbols() {
+        let symbols = extract("Python", "a.py", CODE).unwrap();
+        let parts = split(CODE, Some(&symbols), 5, lines);
+        assert_eq!(parts.first().unwrap().0, 0);
+        assert_eq!(parts.last().unwrap().1, CODE.len());
+        for w in parts.windows(2) {
+            assert_eq!(w[0].1, w[1].0);
+        }
+        for (start, end) in parts.iter() {
+            assert!(lines(&CODE[*start..*end]) <= 5);
+            // no symbol is cut in two
+            for s in symbols.iter() {
+                assert!(s.end_byte <= *start || s.start_byte >= *start && s.end_byte <= *end || s.start_byte >= *end);
+            }
+        }
+    }
+
+    #[test]
+    fn split_small_file_is_one_part() {
+        assert_eq!(split(CODE, None, 100, lines), vec![(0, CODE.len())]);
+    }
+
+    #[test]
+    fn split_without_symbols_at_column_zero() {
+        let parts = split(CODE, None, 4, lines);
+        for (start, _) in parts.iter() {
+            assert!(*start == 0 || CODE[..*start].ends_with('\n'));
+            assert!(!CODE[*start..].starts_with(' '));
+        }
+    }
+
+    #[test]
+    fn split_large_piece_at_every_line() {
+        let code = "def f():\n    a\n    b\n    c\n    d\n";
+        let parts = split(code, None, 2, lines);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(&code[parts[0].0..parts[0].1], "def f():\n    a\n");
+    }
+
+    #[test]
+    fn extract_python() {
+        let symbols = extract("Python", "a.py", CODE).unwrap();
+        let found: Vec<String> = symbols.iter().map(|s| s.describe()).collect();
+        assert_eq!(found, vec![
+            "function a (lines 3-5)",
+            "function b (lines 7-8)",
+            "class C (lines 10-12)",
+            "function m (lines 11-12)",
+        ]);
+        assert_eq!(symbols[1].source_code, "def b():\n    return 2");
+        assert_eq!(&CODE[symbols[1].start_byte..symbols[1].end_byte], symbols[1].source_code);
+    }
+
+    #[test]
+    fn extract_without_grammar() {
+        assert!(extract("Haskell", "a.hs", "main = pure ()").is_none());
+    }
+
+    #[test]
+    fn locate_lines() {
+        assert_eq!(locate(CODE, "def b():\n    return 2"), Some((44, 65, 7, 8)));
+        assert_eq!(locate(CODE, "  "), None);
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use tiktoken_rs::{
+    self,
+    tokenizer::{get_tokenizer, Tokenizer},
+    CoreBPE,
+};
+
+/// counts tokens the way the model does before anything is sent.
+///
+/// models tiktoken does not know (ollama...) are counted with cl100k_base,
+/// which is close enough for a budget. the local embedding model brings
+/// its own tokenizer, its limit is much smaller and exact
+pub struct TokenCounter {
+    encoder: Encoder,
+}
+
+enum Encoder {
+    Bpe(Arc<Mutex<CoreBPE>>),
+    Local(Box<tokenizers::Tokenizer>),
+}
+
+impl TokenCounter {
+    pub fn new(model: &str) -> Self {
+        let bpe = match get_tokenizer(model) {
+            Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+            Some(Tokenizer::P50kBase) => tiktoken_rs::p50k_base_singleton(),
+            Some(Tokenizer::P50kEdit) => tiktoken_rs::p50k_edit_singleton(),
+            Some(Tokenizer::R50kBase) | Some(Tokenizer::Gpt2) => tiktoken_rs::r50k_base_singleton(),
+            _ => tiktoken_rs::cl100k_base_singleton(),
+        };
+        Self { encoder: Encoder::Bpe(bpe) }
+    }
+
+    /// counts with a huggingface tokenizer, without its padding and truncation
+    pub fn local(mut tokenizer: tokenizers::Tokenizer) -> Self {
+        tokenizer.with_padding(None);
+        let _ = tokenizer.with_truncation(None);
+        Self { encoder: Encoder::Local(Box::new(tokenizer)) }
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        match self.encoder {
+            Encoder::Bpe(ref bpe) => bpe.lock().encode_ordinary(text).len(),
+            Encoder::Local(ref tokenizer) => tokenizer.encode(text, false)
+                .map(|e| e.len())
+                .unwrap_or(0),
+        }
+    }
+
+    /// split on token boundaries, every chunk has at most `max_tokens` tokens
+    /// and repeats the last `overlap` tokens of the chunk before it.
+    /// gives the chunks with their token counts
+    pub fn split(&self, text: &str, max_tokens: usize, overlap: usize) -> Vec<(String, usize)> {
+        match self.encoder {
+            Encoder::Bpe(ref bpe) => {
+                let bpe = bpe.lock();
+                let tokens = bpe.encode_ordinary(text);
+                windows(tokens.len(), max_tokens, overlap)
+                    .into_iter()
+                    .map(|(start, end)| match (start, end) {
+                        (0, end) if end == tokens.len() => (text.to_string(), end),
+                        // a chunk border can cut a multi-byte character in two
+                        _ => (String::from_utf8_lossy(&bpe._decode_native(&tokens[start..end])).to_string(), end - start),
+                    })
+                    .collect()
+            },
+            Encoder::Local(ref tokenizer) => {
+                let offsets = match tokenizer.encode(text, false) {
+                    Ok(e) => e.get_offsets().to_vec(),
+                    Err(_) => return vec![(text.to_string(), 0)],
+                };
+                windows(offsets.len(), max_tokens, overlap)
+                    .into_iter()
+                    .map(|(start, end)| match (start, end) {
+                        (0, end) if end == offsets.len() => (text.to_string(), end),
+                        _ => (text[offsets[start].0..offsets[end - 1].1].to_string(), end - start),
+                    })
+                    .collect()
+            },
+        }
+    }
+}
+
+/// the [start, end) token ranges of the chunks of `len` tokens
+fn windows(len: usize, max_tokens: usize, overlap: usize) -> Vec<(usize, usize)> {
+    if len <= max_tokens {
+        return vec![(0, len)];
+    }
+    let step = max_tokens.saturating_sub(overlap).max(1);
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + max_tokens).min(len);
+        chunks.push((start, end));
+        if end == len {
+            break;
+        }
+        start += step;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_overlap() {
+        assert_eq!(windows(5, 10, 2), vec![(0, 5)]);
+        assert_eq!(windows(10, 4, 1), vec![(0, 4), (3, 7), (6, 10)]);
+        // an overlap as large as the chunk still moves forward
+        assert_eq!(windows(3, 2, 2), vec![(0, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn split_bpe() {
+        let counter = TokenCounter::new("gpt-4o");
+        let text = "one two three four five six seven eight nine ten";
+        assert_eq!(counter.split(text, 100, 0), vec![(text.to_string(), counter.count(text))]);
+
+        let chunks = counter.split(text, 4, 0);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|(_, tokens)| *tokens <= 4));
+        assert_eq!(chunks.iter().map(|(c, _)| c.as_str()).collect::<String>(), text);
+    }
+}
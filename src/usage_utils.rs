@@ -0,0 +1,532 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use arrow::array::Array;
+use arrow_array::Float32Array;
+
+use async_trait::async_trait;
+use futures::{future, stream, StreamExt};
+
+use chrono::Local;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{doc_utils, prompt_utils, structs};
+
+use crate::env;
+
+use crate::config::Config;
+
+use crate::providers::{
+    AskStream,
+    ChatProvider,
+    EmbeddingProvider,
+    ProviderError,
+};
+
+use crate::tokenizer_utils::TokenCounter;
+
+/// one call to a model, a line of `.readit/usage.jsonl`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub time: i64,
+    pub day: String,
+    pub command: String,
+    pub kind: String,
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+/// appends the tokens of every call to the ledger of the project
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    path: PathBuf,
+    command: String,
+    budget: Arc<Budget>,
+}
+
+impl Ledger {
+    pub fn new(env: &env::Env) -> Self {
+        Self {
+            path: Self::path(env),
+            command: env.command.clone(),
+            budget: env.budget.clone(),
+        }
+    }
+
+    pub fn path(env: &env::Env) -> PathBuf {
+        Path::new(&env.temp_dir).join("usage.jsonl")
+    }
+
+    pub fn add(&self, kind: &str, model: &str, usage: structs::Usage) {
+        self.budget.spend(model, usage);
+        let now = Local::now();
+        let entry = LedgerEntry {
+            time: now.timestamp(),
+            day: now.format("%Y-%m-%d").to_string(),
+            command: self.command.clone(),
+            kind: kind.to_string(),
+            model: model.to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        };
+        let line = format!("{}\n", serde_json::to_string(&entry).unwrap());
+        match OpenOptions::new().create(true).append(true).open(&self.path) {
+            Ok(mut f) => {
+                let _ = f.write_all(line.as_bytes());
+            },
+            Err(e) => println!("can not write usage ledger {:?}: {}", self.path, e),
+        }
+    }
+
+    pub fn entries(path: &Path) -> Vec<LedgerEntry> {
+        match fs::read_to_string(path) {
+            Err(_) => Vec::new(),
+            Ok(s) => s
+                .lines()
+                .filter_map(|l| serde_json::from_str(l).ok())
+                .collect(),
+        }
+    }
+}
+
+/// the name a chat model has in the ledger and the price table. models of
+/// ollama are `ollama:qwen2.5-coder`, llama.cpp answers with whatever model
+/// it loaded, so it is only `llamacpp`
+pub fn chat_model_name(config: &Config, model: &str) -> String {
+    match config.chat_provider().as_str() {
+        "ollama" => format!("ollama:{}", model),
+        "llamacpp" => "llamacpp".to_string(),
+        _ => model.to_string(),
+    }
+}
+
+/// the name of the embedding model in the ledger and the price table
+pub fn embedding_model_name(config: &Config) -> String {
+    match config.embedding_provider().as_str() {
+        "local" => "local".to_string(),
+        _ => config.embedding_model(),
+    }
+}
+
+/// price of the tokens, None when the model is not in the price table
+pub fn cost(config: &Config, model: &str, prompt_tokens: u64, completion_tokens: u64) -> Option<f64> {
+    config.price(model).map(|p| {
+        (prompt_tokens as f64 * p.prompt + completion_tokens as f64 * p.completion) / 1_000_000.0
+    })
+}
+
+#[derive(Debug, Default)]
+struct Spent {
+    tokens: u64,
+    cost: f64,
+    reserved_tokens: u64,
+    reserved_cost: f64,
+}
+
+/// the spend limit of a command, shared by all of its tasks, 0 is no limit
+///
+/// jobs reserve their estimate before they start and release it when done,
+/// the ledger adds what was really spent
+#[derive(Debug)]
+pub struct Budget {
+    config: Config,
+    max_tokens: u64,
+    max_cost: f64,
+    spent: Mutex<Spent>,
+}
+
+impl Budget {
+    pub fn new(config: &Config, max_tokens: u64, max_cost: f64) -> Self {
+        Self {
+            config: config.clone(),
+            max_tokens,
+            max_cost,
+            spent: Mutex::new(Spent::default()),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.max_tokens > 0 || self.max_cost > 0.0
+    }
+
+    fn over(&self, tokens: u64, cost: f64) -> bool {
+        (self.max_tokens > 0 && tokens > self.max_tokens)
+            || (self.max_cost > 0.0 && cost > self.max_cost)
+    }
+
+    /// whether an estimate fits in the budget at all
+    pub fn fits(&self, tokens: u64, cost: f64) -> bool {
+        !self.over(tokens, cost)
+    }
+
+    pub fn spend(&self, model: &str, usage: structs::Usage) {
+        let mut spent = self.spent.lock().unwrap();
+        spent.tokens += usage.total() as u64;
+        spent.cost += cost(
+            &self.config, model, usage.prompt_tokens as u64, usage.completion_tokens as u64
+        ).unwrap_or(0.0);
+    }
+
+    /// true when what was spent reached the limit
+    pub fn exceeded(&self) -> bool {
+        let spent = self.spent.lock().unwrap();
+        self.is_limited() && (
+            (self.max_tokens > 0 && spent.tokens >= self.max_tokens)
+            || (self.max_cost > 0.0 && spent.cost >= self.max_cost)
+        )
+    }
+
+    /// holds the estimate of a job, false when it does not fit anymore
+    pub fn reserve(&self, tokens: u64, cost: f64) -> bool {
+        let mut spent = self.spent.lock().unwrap();
+        if self.over(
+            spent.tokens + spent.reserved_tokens + tokens,
+            spent.cost + spent.reserved_cost + cost
+        ) {
+            return false;
+        }
+        spent.reserved_tokens += tokens;
+        spent.reserved_cost += cost;
+        true
+    }
+
+    pub fn release(&self, tokens: u64, cost: f64) {
+        let mut spent = self.spent.lock().unwrap();
+        spent.reserved_tokens = spent.reserved_tokens.saturating_sub(tokens);
+        spent.reserved_cost = (spent.reserved_cost - cost).max(0.0);
+    }
+
+    /// tokens and cost spent so far
+    pub fn spent(&self) -> (u64, f64) {
+        let spent = self.spent.lock().unwrap();
+        (spent.tokens, spent.cost)
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (tokens, cost) = self.spent();
+        write!(f, "spent {} tokens, ${:.4}", tokens, cost)?;
+        if self.max_tokens > 0 {
+            write!(f, ", max {} tokens", self.max_tokens)?;
+        }
+        if self.max_cost > 0.0 {
+            write!(f, ", max ${:.4}", self.max_cost)?;
+        }
+        Ok(())
+    }
+}
+
+/// rough tokens and cost of indexing a file, an analyse call whose answer
+/// is about as long as the code, and embeddings of the file and its symbols
+pub fn estimate(config: &Config, programming_lang: &str, code: &str) -> (u64, f64) {
+    let code_tokens = TokenCounter::new(&config.embedding_model()).count(code) as u64;
+    let embedding_tokens = code_tokens * 2;
+    let embedding_model = embedding_model_name(config);
+
+    // docs are only embedded, by section
+    if doc_utils::is_doc(programming_lang) {
+        return (code_tokens, cost(config, &embedding_model, code_tokens, 0).unwrap_or(0.0));
+    }
+
+    let prompt_tokens = TokenCounter::new(&config.analyse_model()).count(
+        &prompt_utils::analyse_source_file_prompt(
+            programming_lang.to_string(), code.to_string(), config.language()
+        )
+    );
+    // files over analyse_max_tokens are analysed in parts, about the same tokens
+    let (prompt_tokens, completion_tokens) = (prompt_tokens as u64, code_tokens);
+
+    let analyse_model = chat_model_name(config, &config.analyse_model());
+    let c = cost(config, &analyse_model, prompt_tokens, completion_tokens).unwrap_or(0.0)
+        + cost(config, &embedding_model, embedding_tokens, 0).unwrap_or(0.0);
+    (prompt_tokens + completion_tokens + embedding_tokens, c)
+}
+
+#[derive(Default)]
+struct Total {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+    unpriced: bool,
+}
+
+/// `readit usage`, totals per model, per command and per day
+pub fn report(env: &env::Env) {
+    let entries = Ledger::entries(Ledger::path(env).as_path());
+    if entries.is_empty() {
+        println!("no usage recorded yet");
+        return;
+    }
+
+    let mut per_model: BTreeMap<String, Total> = BTreeMap::new();
+    let mut per_command: BTreeMap<String, Total> = BTreeMap::new();
+    let mut per_day: BTreeMap<String, Total> = BTreeMap::new();
+    let mut all = Total::default();
+
+    for e in entries.iter() {
+        let c = cost(&env.config, &e.model, e.prompt_tokens as u64, e.completion_tokens as u64);
+        for total in [
+            per_model.entry(e.model.clone()).or_default(),
+            per_command.entry(e.command.clone()).or_default(),
+            per_day.entry(e.day.clone()).or_default(),
+            &mut all,
+        ] {
+            total.prompt_tokens += e.prompt_tokens as u64;
+            total.completion_tokens += e.completion_tokens as u64;
+            match c {
+                Some(c) => total.cost += c,
+                None => total.unpriced = true,
+            }
+        }
+    }
+
+    for (title, totals) in [
+        ("model", &per_model),
+        ("command", &per_command),
+        ("day", &per_day),
+    ] {
+        println!("per {}:", title);
+        for (k, t) in totals.iter() {
+            print_total(k, t);
+        }
+        println!();
+    }
+    print_total("total", &all);
+    if all.unpriced {
+        println!("\n* some models are not in the price table, set `prices` in config.yaml");
+    }
+}
+
+fn print_total(name: &str, t: &Total) {
+    println!(
+        "    {:<32} prompt: {:>10}  completion: {:>10}  cost: ${:.4}{}",
+        name,
+        t.prompt_tokens,
+        t.completion_tokens,
+        t.cost,
+        if t.unpriced { " *" } else { "" }
+    );
+}
+
+/// writes the usage of the wrapped chat provider to the ledger
+pub struct LedgerChat {
+    inner: Box<dyn ChatProvider>,
+    ledger: Ledger,
+    chat_model: String,
+    analyse_model: String,
+}
+
+impl LedgerChat {
+    pub fn new(inner: Box<dyn ChatProvider>, env: &env::Env) -> Self {
+        Self {
+            inner,
+            ledger: Ledger::new(env),
+            chat_model: chat_model_name(&env.config, &env.config.chat_model()),
+            analyse_model: chat_model_name(&env.config, &env.config.analyse_model()),
+        }
+    }
+
+    /// streams give no usage, count the prompt and the answer ourselves
+    fn count_stream(&self, kind: &str, prompt: String, stream: AskStream) -> AskStream {
+        let counter = TokenCounter::new(&self.chat_model);
+        let prompt_tokens = counter.count(&prompt) as u32;
+        let answer = Arc::new(Mutex::new(String::new()));
+
+        let _answer = answer.clone();
+        let stream = stream.inspect(move |piece| {
+            if let Ok(p) = piece {
+                _answer.lock().unwrap().push_str(p);
+            }
+        });
+
+        let ledger = self.ledger.clone();
+        let kind = kind.to_string();
+        let model = self.chat_model.clone();
+        let done = stream::once(async move {
+            let completion_tokens = counter.count(&answer.lock().unwrap()) as u32;
+            ledger.add(&kind, &model, structs::Usage { prompt_tokens, completion_tokens });
+        }).filter_map(|_| future::ready(None::<Result<String, ProviderError>>));
+
+        Box::pin(stream.chain(done))
+    }
+
+    /// the usage of a call, also of one whose answer never parsed
+    fn add<T>(&self, kind: &str, model: &str, result: Result<(T, structs::Usage), ProviderError>)
+        -> Result<(T, structs::Usage), ProviderError>
+    {
+        match result {
+            Ok((response, tokens)) => {
+                self.ledger.add(kind, model, tokens);
+                Ok((response, tokens))
+            },
+            Err(ProviderError::InvalidJson(m, tokens)) => {
+                self.ledger.add(kind, model, tokens);
+                Err(ProviderError::InvalidJson(m, tokens))
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatProvider for LedgerChat {
+
+    async fn analyse_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<(structs::GPTResponse, structs::Usage), ProviderError>
+    {
+        let result = self.inner.analyse_source(code_string, programming_lang, language).await;
+        self.add("analyse_source", &self.analyse_model, result)
+    }
+
+    async fn analyse_symbols(&self,
+        code_string: String, programming_lang: String, symbols: Vec<String>, language: String
+    ) -> Result<(structs::GPTSymbolsResponse, structs::Usage), ProviderError>
+    {
+        let result = self.inner.analyse_symbols(code_string, programming_lang, symbols, language).await;
+        self.add("analyse_symbols", &self.analyse_model, result)
+    }
+
+    async fn split_source(&self,
+        code_string: String, programming_lang: String, language: String
+    ) -> Result<structs::GPTCodeSplitResponse, ProviderError>
+    {
+        self.inner.split_source(code_string, programming_lang, language).await
+    }
+
+    async fn ask(&self,
+        query: String, code_list: Vec<String>, language: String
+    ) -> Result<AskStream, ProviderError>
+    {
+        let prompt = prompt_utils::ask_prompt(query.clone(), code_list.clone(), language.clone());
+        let stream = self.inner.ask(query, code_list, language).await?;
+        Ok(self.count_stream("ask", prompt, stream))
+    }
+
+    async fn chat(&self, message: String) -> Result<AskStream, ProviderError> {
+        let stream = self.inner.chat(message.clone()).await?;
+        Ok(self.count_stream("chat", message, stream))
+    }
+
+    async fn summarize(&self, query: String, language: String)
+        -> Result<(String, structs::Usage), ProviderError>
+    {
+        let result = self.inner.summarize(query, language).await;
+        self.add("summarize", &self.chat_model, result)
+    }
+
+    async fn merge_purposes(&self,
+        parts: Vec<String>, programming_lang: String, language: String
+    ) -> Result<(String, structs::Usage), ProviderError>
+    {
+        let result = self.inner.merge_purposes(parts, programming_lang, language).await;
+        self.add("merge_purposes", &self.analyse_model, result)
+    }
+}
+
+/// writes the usage of the wrapped embedding provider to the ledger
+pub struct LedgerEmbedding {
+    inner: Box<dyn EmbeddingProvider>,
+    ledger: Ledger,
+    embedding_model: String,
+}
+
+impl LedgerEmbedding {
+    pub fn new(inner: Box<dyn EmbeddingProvider>, env: &env::Env) -> Self {
+        Self {
+            inner,
+            ledger: Ledger::new(env),
+            embedding_model: embedding_model_name(&env.config),
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for LedgerEmbedding {
+
+    async fn embedding_compute(&self, source: Arc<dyn Array>)
+        -> Result<(Float32Array, u32), ProviderError>
+    {
+        let (embedding, tokens) = self.inner.embedding_compute(source).await?;
+        self.ledger.add("embedding", &self.embedding_model, structs::Usage {
+            prompt_tokens: tokens,
+            completion_tokens: 0,
+        });
+        Ok((embedding, tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn budget_reserve_and_release() {
+        let budget = Budget::new(&config("{}"), 100, 0.0);
+        assert!(budget.is_limited());
+        assert!(budget.fits(100, 0.0));
+        assert!(!budget.fits(101, 0.0));
+
+        assert!(budget.reserve(60, 0.0));
+        // 60 held, 50 more is over
+        assert!(!budget.reserve(50, 0.0));
+        budget.release(60, 0.0);
+        assert!(budget.reserve(50, 0.0));
+        // releasing more than held does not go below 0
+        budget.release(500, 0.0);
+        assert!(budget.reserve(100, 0.0));
+    }
+
+    #[test]
+    fn budget_exceeded_by_spending() {
+        let budget = Budget::new(&config("{}"), 0, 1.0);
+        assert!(!budget.exceeded());
+        // 100k prompt tokens of gpt-4o are $0.25
+        let usage = structs::Usage { prompt_tokens: 100_000, completion_tokens: 0 };
+        for _ in 0..3 {
+            budget.spend("gpt-4o", usage);
+        }
+        assert!(!budget.exceeded());
+        assert!(!budget.reserve(0, 0.5));
+        assert!(budget.reserve(0, 0.2));
+        budget.spend("gpt-4o", usage);
+        assert!(budget.exceeded());
+        assert_eq!(budget.spent().0, 400_000);
+        assert!(budget.to_string().starts_with("spent 400000 tokens, $1.0000, max $1.0000"));
+
+        // no limit is never exceeded
+        let unlimited = Budget::new(&config("{}"), 0, 0.0);
+        unlimited.spend("gpt-4o", usage);
+        assert!(!unlimited.exceeded() && unlimited.reserve(u64::MAX / 2, 1e9));
+    }
+
+    #[test]
+    fn ledger_model_names() {
+        let openai = config("chat_model: gpt-4o");
+        assert_eq!(chat_model_name(&openai, "gpt-4o"), "gpt-4o");
+        assert!(cost(&openai, "gpt-4o", 1_000_000, 0).unwrap() > 0.0);
+
+        // the default chat_model is an openai one, it costs nothing on ollama
+        let ollama = config("chat_provider: ollama");
+        let model = chat_model_name(&ollama, &ollama.chat_model());
+        assert_eq!(model, format!("ollama:{}", ollama.chat_model()));
+        assert_eq!(cost(&ollama, &model, 1_000_000, 1_000_000), Some(0.0));
+
+        let llamacpp = config("chat_provider: llamacpp\nembedding_provider: local");
+        assert_eq!(chat_model_name(&llamacpp, "gpt-4o"), "llamacpp");
+        let (tokens, cost) = estimate(&llamacpp, "Python", "def f():\n    return 1\n");
+        assert!(tokens > 0);
+        assert_eq!(cost, 0.0);
+    }
+}
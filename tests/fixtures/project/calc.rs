@@ -0,0 +1,14 @@
+pub struct Calculator {
+    total: i64,
+}
+
+impl Calculator {
+    pub fn add(&mut self, value: i64) -> i64 {
+        self.total += value;
+        self.total
+    }
+}
+
+pub fn square(value: i64) -> i64 {
+    value * value
+}
@@ -0,0 +1,10 @@
+class Greeter:
+    def __init__(self, name):
+        self.name = name
+
+    def greet(self):
+        return f"Hello, {self.name}!"
+
+
+def shout(text):
+    return text.upper() + "!"
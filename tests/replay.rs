@@ -0,0 +1,150 @@
+//! init and ask on a fixture project, every model answer read back from the
+//! recorded `.readit/cassette.jsonl`, so nothing leaves the machine
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use tempfile::TempDir;
+
+/// a copy of the fixture project, the run writes its index next to the cassette
+struct Project {
+    home: TempDir,
+    dir: TempDir,
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+impl Project {
+    fn new() -> Self {
+        let project = Self {
+            home: tempfile::tempdir().unwrap(),
+            dir: tempfile::tempdir().unwrap(),
+        };
+        copy_dir(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/project"),
+            project.dir.path()
+        );
+        project
+    }
+
+    fn readit(&self, args: &[&str]) -> String {
+        let (success, stdout, stderr) = self.run(args);
+        assert!(success, "readit {:?} failed\n{}\n{}", args, stdout, stderr);
+        stdout
+    }
+
+    /// whether readit exited with 0, stdout and stderr
+    fn run(&self, args: &[&str]) -> (bool, String, String) {
+        let Output { status, stdout, stderr } = Command::new(env!("CARGO_BIN_EXE_readit"))
+            .arg("-p")
+            .arg(self.dir.path())
+            .args(args)
+            .env("HOME", self.home.path())
+            .env_remove("OPENAI_KEY")
+            .env_remove("OPENAI_BASE")
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        (
+            status.success(),
+            String::from_utf8_lossy(&stdout).to_string(),
+            String::from_utf8_lossy(&stderr).to_string(),
+        )
+    }
+}
+
+#[test]
+fn init_and_ask() {
+    let project = Project::new();
+
+    let out = project.readit(&["init"]);
+    assert!(out.contains("analyse: \"calc.rs\""), "{}", out);
+    assert!(out.contains("analyse: \"greet.py\""), "{}", out);
+    assert!(out.contains("Embedding Done"), "{}", out);
+    assert!(!out.contains("failed"), "{}", out);
+    assert!(project.dir.path().join(".readit/db").exists());
+
+    let out = project.readit(&["ask", "how do I shout a text?"]);
+    assert!(out.contains("find 10 answers"), "{}", out);
+    assert!(out.contains("greet.py:9-10  function shout"), "{}", out);
+    assert!(out.contains("calc.rs:12-14  function square"), "{}", out);
+    assert!(out.contains("Use `shout` in greet.py, it upper-cases the text and adds an exclamation mark."), "{}", out);
+    // nothing changed since init, nothing is analysed again
+    assert!(!out.contains("analyse:"), "{}", out);
+}
+
+#[test]
+fn ask_without_recording() {
+    let project = Project::new();
+    project.readit(&["init"]);
+
+    // the question was never recorded, the miss is reported and nothing panics
+    let out = project.readit(&["ask", "what does square return?"]);
+    assert!(out.contains("no recorded response for embedding in the cassette"), "{}", out);
+}
+
+#[test]
+fn init_with_a_failed_file() {
+    let project = Project::new();
+    // changed since the recording, its analysis is not in the cassette
+    fs::write(project.dir.path().join("calc.rs"), "fn cube(x: i32) -> i32 {\n    x * x * x\n}\n").unwrap();
+
+    let (success, out, _) = project.run(&["init"]);
+    assert!(!success, "{}", out);
+    assert!(out.contains("calc.rs failed to analyse"), "{}", out);
+    assert!(out.contains("1 files failed"), "{}", out);
+    assert!(!out.contains("Embedding Done"), "{}", out);
+}
+
+#[test]
+fn deleted_file_leaves_the_index() {
+    let project = Project::new();
+    project.readit(&["init"]);
+    fs::remove_file(project.dir.path().join("greet.py")).unwrap();
+
+    let out = project.readit(&["init", "--resume"]);
+    assert!(out.contains("removed 5 rows of 1 files that are deleted or ignored"), "{}", out);
+    // the new summary was never recorded, the old one stays
+    assert!(out.contains("failed to summarize the project"), "{}", out);
+
+    let out = project.readit(&["ask", "how do I shout a text?"]);
+    assert!(!out.contains("greet.py:"), "{}", out);
+    assert!(out.contains("whole project"), "{}", out);
+}
+
+#[test]
+fn index_of_another_analyse_model() {
+    let project = Project::new();
+    project.readit(&["init"]);
+
+    let (success, out, _) = project.run(&["-c", "analyse_model=another-model", "ask", "how do I shout a text?"]);
+    assert!(!success, "{}", out);
+    assert!(out.contains("the index was analysed with"), "{}", out);
+    assert!(out.contains("another-model"), "{}", out);
+    assert!(!out.contains("find 10 answers"), "{}", out);
+}
+
+#[test]
+fn skipped_files_only_listed_by_init() {
+    let project = Project::new();
+    fs::write(project.dir.path().join("data.py"), b"\x00\x01\x02").unwrap();
+
+    let out = project.readit(&["init"]);
+    assert!(out.contains("skipped 1 files:\n    data.py: binary"), "{}", out);
+
+    let out = project.readit(&["ask", "how do I shout a text?"]);
+    assert!(!out.contains("skipped"), "{}", out);
+    assert!(out.contains("find 10 answers"), "{}", out);
+}